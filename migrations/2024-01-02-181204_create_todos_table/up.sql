@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE todos (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    description VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX todos_user_id_idx ON todos (user_id);
+
+SELECT diesel_manage_updated_at('todos');
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS todos_user_id_position_idx;
+CREATE INDEX todos_user_id_idx ON todos (user_id);
+
+ALTER TABLE todos
+    DROP COLUMN IF EXISTS position,
+    DROP COLUMN IF EXISTS done;
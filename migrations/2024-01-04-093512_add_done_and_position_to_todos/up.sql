@@ -0,0 +1,16 @@
+-- Your SQL goes here
+ALTER TABLE todos
+    ADD COLUMN done BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+
+-- Existing todos keep their creation order
+UPDATE todos
+SET position = ordered.position
+FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY created_at) - 1 AS position
+    FROM todos
+) AS ordered
+WHERE todos.id = ordered.id;
+
+DROP INDEX IF EXISTS todos_user_id_idx;
+CREATE INDEX todos_user_id_position_idx ON todos (user_id, position);
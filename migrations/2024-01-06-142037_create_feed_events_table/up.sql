@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE feed_events (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    kind VARCHAR(50) NOT NULL,
+    subject VARCHAR(255) NOT NULL DEFAULT '',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- The feed is paged newest-first by (created_at, id)
+CREATE INDEX feed_events_created_at_id_idx ON feed_events (created_at DESC, id DESC);
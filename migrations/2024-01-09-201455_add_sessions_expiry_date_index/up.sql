@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Lets the session reaper find expired rows without scanning the table
+CREATE INDEX sessions_expiry_date_idx ON sessions (expiry_date);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Emails are unique regardless of case, and logins look them up by lower(email)
+CREATE UNIQUE INDEX users_email_lower_idx ON users (lower(email));
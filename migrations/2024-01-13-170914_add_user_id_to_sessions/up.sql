@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- Filled in by the session store from the logged in user, so every session of
+-- a user can be found and revoked
+ALTER TABLE sessions ADD COLUMN user_id uuid REFERENCES users (id) ON DELETE CASCADE;
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
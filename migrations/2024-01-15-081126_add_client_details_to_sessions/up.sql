@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE sessions
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ADD COLUMN last_seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ADD COLUMN ip_address text,
+    ADD COLUMN user_agent text;
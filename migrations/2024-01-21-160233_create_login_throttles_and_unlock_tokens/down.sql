@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE account_unlock_tokens;
+DROP TABLE login_throttles;
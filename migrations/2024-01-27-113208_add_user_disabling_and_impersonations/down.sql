@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE impersonations;
+
+ALTER TABLE users DROP COLUMN disabled_at;
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+-- Disabled users can't log in, admins turn them back on.
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMP;
+
+-- Every time an admin logged in as someone else. `ended_at` stays empty when
+-- the session ended without switching back, e.g. by logging out.
+CREATE TABLE impersonations (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    admin_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    started_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ended_at TIMESTAMP
+);
+
+CREATE INDEX impersonations_admin_id_idx ON impersonations (admin_id);
+CREATE INDEX impersonations_user_id_idx ON impersonations (user_id);
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- Deleted users are kept for a grace period, logging in restores them.
+-- Afterwards the purge deletes the row, and with it everything they own.
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMP;
+
+CREATE INDEX users_deleted_at_idx ON users (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+-- Security relevant events. The actor did it, the target had it done to
+-- them; both stay empty where there is no such user, e.g. a failed login with
+-- an unknown email. Rows outlive purged users, their ids are nulled.
+CREATE TABLE audit_events (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    actor_id uuid REFERENCES users (id) ON DELETE SET NULL,
+    target_id uuid REFERENCES users (id) ON DELETE SET NULL,
+    action VARCHAR(64) NOT NULL,
+    ip_address TEXT,
+    user_agent TEXT,
+    metadata JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX audit_events_created_at_idx ON audit_events (created_at);
+CREATE INDEX audit_events_actor_id_idx ON audit_events (actor_id);
+CREATE INDEX audit_events_target_id_idx ON audit_events (target_id);
+CREATE INDEX audit_events_action_idx ON audit_events (action);
+
+INSERT INTO permissions (name) VALUES ('audit.view');
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id FROM roles CROSS JOIN permissions
+WHERE roles.name = 'admin' AND permissions.name = 'audit.view';
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE email_verification_tokens DROP COLUMN new_email;
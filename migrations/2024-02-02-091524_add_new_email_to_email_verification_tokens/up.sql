@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Set on the tokens of an email change, the email only changes to it once the
+-- link sent there is followed
+ALTER TABLE email_verification_tokens ADD COLUMN new_email VARCHAR(255);
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- When the todo was first completed. Un-checking it keeps the time, so the
+-- feed hears about each todo being completed only once.
+ALTER TABLE todos ADD COLUMN completed_at TIMESTAMP;
+
+UPDATE todos SET completed_at = updated_at WHERE done;
@@ -0,0 +1,153 @@
+use diesel::{pg::PgConnection, Connection};
+use log::{error, info};
+
+use crate::repositories::role_repository::{self, RoleDb};
+use crate::repositories::user_repository::{self, UserDb};
+use crate::{config::Config, db};
+
+const USAGE: &str = "usage: informator [serve | migrate <up|down|status> | roles [list [<email>] | grant <email> <role> | revoke <email> <role>]]";
+
+pub enum Command {
+    Serve,
+    Migrate(MigrateCommand),
+    Roles(RolesCommand),
+}
+
+pub enum MigrateCommand {
+    Up,
+    Down,
+    Status,
+}
+
+/// Roles are only handed out here and by admins, so this is how the first
+/// admin gets theirs.
+pub enum RolesCommand {
+    /// Every role, or only those of the user with the email.
+    List(Option<String>),
+    Grant { email: String, role: String },
+    Revoke { email: String, role: String },
+}
+
+impl Command {
+    /// Parses the process arguments, printing the usage and exiting on
+    /// anything unrecognised. No arguments starts the server.
+    pub fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        match args.as_slice() {
+            [] | ["serve"] => Command::Serve,
+            ["migrate", "up"] => Command::Migrate(MigrateCommand::Up),
+            ["migrate", "down"] => Command::Migrate(MigrateCommand::Down),
+            ["migrate", "status"] => Command::Migrate(MigrateCommand::Status),
+            ["roles"] | ["roles", "list"] => Command::Roles(RolesCommand::List(None)),
+            ["roles", "list", email] => Command::Roles(RolesCommand::List(Some(email.to_string()))),
+            ["roles", "grant", email, role] => Command::Roles(RolesCommand::Grant {
+                email: email.to_string(),
+                role: role.to_string(),
+            }),
+            ["roles", "revoke", email, role] => Command::Roles(RolesCommand::Revoke {
+                email: email.to_string(),
+                role: role.to_string(),
+            }),
+            ["-h" | "--help" | "help"] => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+}
+
+/// Runs a `migrate` subcommand over a single connection and exits with a
+/// non-zero status if it fails.
+pub fn migrate(config: &Config, command: MigrateCommand) {
+    if let Err(err) = run_migrate(config, command) {
+        error!("Migration failed: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run_migrate(config: &Config, command: MigrateCommand) -> Result<(), db::MigrationError> {
+    let mut connection = PgConnection::establish(&config.database.url)?;
+
+    match command {
+        MigrateCommand::Up => {
+            let applied = db::run_pending_migrations(&mut connection)?;
+            if applied.is_empty() {
+                info!("No pending migrations");
+            }
+            for version in applied {
+                info!("Applied migration {}", version);
+            }
+        }
+        MigrateCommand::Down => {
+            let version = db::revert_last_migration(&mut connection)?;
+            info!("Reverted migration {}", version);
+        }
+        MigrateCommand::Status => {
+            for (name, applied) in db::migration_status(&mut connection)? {
+                println!("[{}] {}", if applied { "x" } else { " " }, name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a `roles` subcommand and exits with a non-zero status if it fails.
+pub fn roles(config: &Config, command: RolesCommand) {
+    if let Err(err) = run_roles(config, command) {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run_roles(config: &Config, command: RolesCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let mut connection = PgConnection::establish(&config.database.url)?;
+
+    match command {
+        RolesCommand::List(None) => {
+            for role in role_repository::get_roles(&mut connection)? {
+                println!("{}", role.name);
+            }
+        }
+        RolesCommand::List(Some(email)) => {
+            let user = find_user(&mut connection, &email)?;
+            for role in role_repository::get_by_user_id(&mut connection, user.id)? {
+                println!("{}", role.name);
+            }
+        }
+        RolesCommand::Grant { email, role } => {
+            let user = find_user(&mut connection, &email)?;
+            let role = find_role(&mut connection, &role)?;
+            match role_repository::assign(&mut connection, user.id, role.id)? {
+                true => info!("Granted {} to {}", role.name, user.email),
+                false => info!("{} already has {}", user.email, role.name),
+            }
+        }
+        RolesCommand::Revoke { email, role } => {
+            let user = find_user(&mut connection, &email)?;
+            let role = find_role(&mut connection, &role)?;
+            match role_repository::revoke(&mut connection, user.id, role.id)? {
+                true => info!("Revoked {} from {}", role.name, user.email),
+                false => info!("{} doesn't have {}", user.email, role.name),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn find_user(connection: &mut PgConnection, email: &str) -> Result<UserDb, String> {
+    user_repository::get_by_email(connection, email.to_string())
+        .map_err(|err| format!("no user with email {}: {}", email, err))
+}
+
+fn find_role(connection: &mut PgConnection, name: &str) -> Result<RoleDb, String> {
+    role_repository::get_by_name(connection, name)
+        .map_err(|err| format!("no role named {}: {}", name, err))
+}
@@ -0,0 +1,144 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::header::USER_AGENT;
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tower_sessions::session::Id;
+use tower_sessions::Session;
+
+use crate::errors::AppError;
+use crate::impersonation::Impersonator;
+use crate::repositories::auth_backend::AUTH_DATA_KEY;
+use crate::repositories::postgres_store::PostgresStore;
+
+/// The session key `track_client` keeps the client details under.
+/// `PostgresStore` copies them into their own columns when saving.
+pub const CLIENT_INFO_KEY: &str = "informator.client";
+
+/// How old `seen_at` gets before a request refreshes it. Every refresh is a
+/// session write, so this caps them at one a minute per device.
+const SEEN_AT_RESOLUTION_SECS: i64 = 60;
+
+/// User agents are sent by the client, so only this much of one is kept.
+const USER_AGENT_MAX_LENGTH: usize = 512;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    /// Unix timestamp of the last time the session was refreshed.
+    pub seen_at: i64,
+}
+
+/// The address and user agent of the request, as the audit log records them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestClient {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    /// The admin logged in as the user, who the audit log holds responsible.
+    pub impersonator: Option<Impersonator>,
+}
+
+impl RequestClient {
+    fn from_parts(extensions: &Extensions, headers: &HeaderMap) -> Self {
+        Self {
+            ip_address: extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+            user_agent: headers
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(USER_AGENT_MAX_LENGTH).collect::<String>()),
+            impersonator: None,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestClient {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            impersonator: Impersonator::from_parts(parts).await?,
+            ..Self::from_parts(&parts.extensions, &parts.headers)
+        })
+    }
+}
+
+/// Middleware recording the address and user agent of logged in sessions.
+/// It runs after the handler so that the request logging in is recorded too.
+pub async fn track_client(
+    State(store): State<PostgresStore>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let session = request.extensions().get::<Session>().cloned();
+    let loaded_id = session.as_ref().and_then(|session| session.id());
+    let RequestClient {
+        ip_address,
+        user_agent,
+        ..
+    } = RequestClient::from_parts(request.extensions(), request.headers());
+
+    let response = next.run(request).await;
+
+    if let Some(session) = session {
+        if let Err(err) = touch(&store, &session, loaded_id, ip_address, user_agent).await {
+            warn!("failed to record session client: {}", err);
+        }
+    }
+
+    response
+}
+
+async fn touch(
+    store: &PostgresStore,
+    session: &Session,
+    loaded_id: Option<Id>,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+) -> Result<(), tower_sessions::session::Error> {
+    // Anonymous sessions aren't listed anywhere and logged out ones are gone
+    if session.get_value(AUTH_DATA_KEY).await?.is_none() {
+        return Ok(());
+    }
+
+    let now = Utc::now().timestamp();
+    let previous = session.get::<ClientInfo>(CLIENT_INFO_KEY).await?;
+    let unchanged = previous.is_some_and(|previous| {
+        previous.ip_address == ip_address
+            && previous.user_agent == user_agent
+            && now - previous.seen_at < SEEN_AT_RESOLUTION_SECS
+    });
+    if unchanged {
+        return Ok(());
+    }
+
+    // A session that came with the request may have been revoked while the
+    // handler ran, and writing to it would save it again. Sessions this
+    // request logged in have a new id and aren't stored yet.
+    if let Some(id) = loaded_id.filter(|id| session.id() == Some(*id)) {
+        if !store.exists(id).await? {
+            return Ok(());
+        }
+    }
+
+    session
+        .insert(
+            CLIENT_INFO_KEY,
+            ClientInfo {
+                ip_address,
+                user_agent,
+                seen_at: now,
+            },
+        )
+        .await
+}
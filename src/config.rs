@@ -0,0 +1,592 @@
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::{env, fs};
+
+use serde::Deserialize;
+
+use crate::repositories::login_throttle_repository::LockoutPolicy;
+use crate::repositories::postgres_store::{is_valid_identifier, DEFAULT_DELETE_BATCH_SIZE};
+
+/// Where the optional TOML config file is looked up when `APP_CONFIG_FILE`
+/// isn't set. See `config.example.toml` for the format.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Encrypts TOTP secrets in development when `auth.totp_key` isn't set. It is
+/// public, so production refuses to start without a key of its own.
+const DEVELOPMENT_TOTP_KEY: [u8; 32] = *b"informator-development-totp-key!";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to parse config file {path}: {source}")]
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+    #[error("environment variable {name}={value:?} is invalid: {reason}")]
+    Env {
+        name: &'static str,
+        value: String,
+        reason: String,
+    },
+    #[error("{0}")]
+    Invalid(String),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    #[default]
+    Development,
+    Production,
+}
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "development" => Ok(Environment::Development),
+            "production" => Ok(Environment::Production),
+            _ => Err("expected `development` or `production`".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Where users reach the app, used for links in emails.
+    pub public_url: String,
+    /// How long to wait for in-flight requests and background tasks once a
+    /// shutdown signal is received.
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 3000,
+            public_url: "http://localhost:3000".to_string(),
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub pool_size: u32,
+    /// Apply pending migrations before the server starts accepting requests.
+    pub migrate_on_boot: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            pool_size: 5,
+            migrate_on_boot: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Sessions expire after this many seconds of inactivity.
+    pub expiry_secs: i64,
+    /// Only send the session cookie over HTTPS. Defaults to `true` in
+    /// production and `false` otherwise.
+    pub secure: Option<bool>,
+    /// Overrides the session store's default `public` schema.
+    pub schema_name: Option<String>,
+    /// Overrides the session store's default `sessions` table.
+    pub table_name: Option<String>,
+    pub reaper_interval_secs: u64,
+    pub reaper_batch_size: i64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            expiry_secs: 24 * 60 * 60,
+            secure: None,
+            schema_name: None,
+            table_name: None,
+            reaper_interval_secs: 60,
+            reaper_batch_size: DEFAULT_DELETE_BATCH_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Keep users out of the app until they follow the link in the
+    /// verification email.
+    pub require_verified_email: bool,
+    /// Failed logins for one email before it gets locked.
+    pub lockout_threshold: i32,
+    /// Failed logins from one IP address before it gets locked.
+    pub ip_lockout_threshold: i32,
+    /// The first lock, doubled by every further failure up to
+    /// `lockout_max_secs`.
+    pub lockout_base_secs: i64,
+    pub lockout_max_secs: i64,
+    /// 32 bytes as 64 hex digits, encrypts the TOTP secrets of two-factor
+    /// authentication. Required in production.
+    pub totp_key: Option<String>,
+    /// Days a deleted account can still be restored by logging in, before
+    /// it is purged for good.
+    pub deletion_grace_days: i64,
+    /// How often deleted accounts past their grace period are purged.
+    pub purge_interval_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            require_verified_email: true,
+            lockout_threshold: 5,
+            ip_lockout_threshold: 20,
+            lockout_base_secs: 30,
+            lockout_max_secs: 60 * 60,
+            totp_key: None,
+            deletion_grace_days: 30,
+            purge_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn account_lockout(&self) -> LockoutPolicy {
+        LockoutPolicy {
+            threshold: self.lockout_threshold,
+            base_secs: self.lockout_base_secs,
+            max_secs: self.lockout_max_secs,
+        }
+    }
+
+    pub fn ip_lockout(&self) -> LockoutPolicy {
+        LockoutPolicy {
+            threshold: self.ip_lockout_threshold,
+            ..self.account_lockout()
+        }
+    }
+
+    /// The decoded `totp_key`, or the development key when it isn't set.
+    /// `Config::validate` makes sure it decodes.
+    pub fn totp_key(&self) -> [u8; 32] {
+        self.totp_key
+            .as_deref()
+            .and_then(decode_key)
+            .unwrap_or(DEVELOPMENT_TOTP_KEY)
+    }
+}
+
+fn decode_key(hex: &str) -> Option<[u8; 32]> {
+    let bytes = data_encoding::HEXLOWER_PERMISSIVE.decode(hex.as_bytes()).ok()?;
+
+    bytes.try_into().ok()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Write emails to the log.
+    #[default]
+    Log,
+    /// Write emails as `.eml` files into `mail.directory`.
+    File,
+}
+
+impl FromStr for MailTransport {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "log" => Ok(MailTransport::Log),
+            "file" => Ok(MailTransport::File),
+            _ => Err("expected `log` or `file`".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    /// The `From` header of every email.
+    pub from: String,
+    pub directory: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: MailTransport::Log,
+            from: "Informator <no-reply@localhost>".to_string(),
+            directory: "mail".to_string(),
+        }
+    }
+}
+
+/// Application configuration. Values come from the defaults below, then the
+/// optional TOML file, then environment variables, which win.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub environment: Environment,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub session: SessionConfig,
+    pub auth: AuthConfig,
+    pub mail: MailConfig,
+}
+
+impl Config {
+    /// Loads the configuration from `APP_CONFIG_FILE` (or `config.toml` if it
+    /// exists) and the process environment.
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = env::var("APP_CONFIG_FILE").ok();
+        let contents = match &path {
+            Some(path) => Some((path.clone(), read_file(path)?)),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Some((
+                DEFAULT_CONFIG_FILE.to_string(),
+                read_file(DEFAULT_CONFIG_FILE)?,
+            )),
+            None => None,
+        };
+
+        Self::from_sources(contents, |name| env::var(name).ok())
+    }
+
+    /// Builds the configuration from an optional `(path, contents)` TOML file
+    /// and an environment lookup function.
+    pub fn from_sources(
+        file: Option<(String, String)>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut config = match file {
+            Some((path, contents)) => toml::from_str::<Config>(&contents)
+                .map_err(|source| ConfigError::Parse { path, source })?,
+            None => Config::default(),
+        };
+
+        override_from_env(&env, "APP_ENVIRONMENT", &mut config.environment)?;
+        override_from_env(&env, "APP_HOST", &mut config.server.host)?;
+        override_from_env(&env, "APP_PORT", &mut config.server.port)?;
+        override_from_env(&env, "APP_PUBLIC_URL", &mut config.server.public_url)?;
+        override_from_env(
+            &env,
+            "APP_SHUTDOWN_TIMEOUT_SECS",
+            &mut config.server.shutdown_timeout_secs,
+        )?;
+        override_from_env(&env, "DATABASE_URL", &mut config.database.url)?;
+        override_from_env(&env, "DATABASE_POOL_SIZE", &mut config.database.pool_size)?;
+        override_from_env(
+            &env,
+            "DATABASE_MIGRATE_ON_BOOT",
+            &mut config.database.migrate_on_boot,
+        )?;
+        override_from_env(&env, "SESSION_EXPIRY_SECS", &mut config.session.expiry_secs)?;
+        override_option_from_env(&env, "SESSION_SECURE", &mut config.session.secure)?;
+        override_option_from_env(&env, "SESSION_SCHEMA_NAME", &mut config.session.schema_name)?;
+        override_option_from_env(&env, "SESSION_TABLE_NAME", &mut config.session.table_name)?;
+        override_from_env(
+            &env,
+            "SESSION_REAPER_INTERVAL_SECS",
+            &mut config.session.reaper_interval_secs,
+        )?;
+        override_from_env(
+            &env,
+            "SESSION_REAPER_BATCH_SIZE",
+            &mut config.session.reaper_batch_size,
+        )?;
+        override_from_env(
+            &env,
+            "AUTH_REQUIRE_VERIFIED_EMAIL",
+            &mut config.auth.require_verified_email,
+        )?;
+        override_from_env(&env, "AUTH_LOCKOUT_THRESHOLD", &mut config.auth.lockout_threshold)?;
+        override_from_env(
+            &env,
+            "AUTH_IP_LOCKOUT_THRESHOLD",
+            &mut config.auth.ip_lockout_threshold,
+        )?;
+        override_from_env(&env, "AUTH_LOCKOUT_BASE_SECS", &mut config.auth.lockout_base_secs)?;
+        override_from_env(&env, "AUTH_LOCKOUT_MAX_SECS", &mut config.auth.lockout_max_secs)?;
+        override_option_from_env(&env, "AUTH_TOTP_KEY", &mut config.auth.totp_key)?;
+        override_from_env(
+            &env,
+            "AUTH_DELETION_GRACE_DAYS",
+            &mut config.auth.deletion_grace_days,
+        )?;
+        override_from_env(
+            &env,
+            "AUTH_PURGE_INTERVAL_SECS",
+            &mut config.auth.purge_interval_secs,
+        )?;
+        override_from_env(&env, "MAIL_TRANSPORT", &mut config.mail.transport)?;
+        override_from_env(&env, "MAIL_FROM", &mut config.mail.from)?;
+        override_from_env(&env, "MAIL_DIRECTORY", &mut config.mail.directory)?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self.server.public_url.starts_with("http://")
+            && !self.server.public_url.starts_with("https://")
+        {
+            return Err(ConfigError::Invalid(
+                "public url must start with http:// or https://".to_string(),
+            ));
+        }
+        if self.database.url.is_empty() {
+            return Err(ConfigError::Invalid(
+                "database url must be set, either as DATABASE_URL or `database.url`".to_string(),
+            ));
+        }
+        if self.database.pool_size == 0 {
+            return Err(ConfigError::Invalid(
+                "database pool size must be at least 1".to_string(),
+            ));
+        }
+        if self.session.expiry_secs <= 0 {
+            return Err(ConfigError::Invalid(
+                "session expiry must be a positive number of seconds".to_string(),
+            ));
+        }
+        for (name, value) in [
+            ("schema", &self.session.schema_name),
+            ("table", &self.session.table_name),
+        ] {
+            if let Some(value) = value.as_deref().filter(|value| !is_valid_identifier(value)) {
+                return Err(ConfigError::Invalid(format!(
+                    "session {} name {:?} is not a valid PostgreSQL identifier",
+                    name, value
+                )));
+            }
+        }
+        if self.session.reaper_interval_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "session reaper interval must be at least 1 second".to_string(),
+            ));
+        }
+        if self.session.reaper_batch_size <= 0 {
+            return Err(ConfigError::Invalid(
+                "session reaper batch size must be at least 1".to_string(),
+            ));
+        }
+
+        if self.auth.lockout_threshold < 1 || self.auth.ip_lockout_threshold < 1 {
+            return Err(ConfigError::Invalid(
+                "lockout thresholds must be at least 1".to_string(),
+            ));
+        }
+        if self.auth.lockout_base_secs < 1 || self.auth.lockout_max_secs < self.auth.lockout_base_secs
+        {
+            return Err(ConfigError::Invalid(
+                "lockout base must be at least 1 second and at most the lockout maximum"
+                    .to_string(),
+            ));
+        }
+        if self.auth.deletion_grace_days < 0 {
+            return Err(ConfigError::Invalid(
+                "deletion grace period can't be negative".to_string(),
+            ));
+        }
+        if self.auth.purge_interval_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "purge interval must be at least 1 second".to_string(),
+            ));
+        }
+        match &self.auth.totp_key {
+            Some(key) if decode_key(key).is_none() => {
+                return Err(ConfigError::Invalid(
+                    "totp key must be 32 bytes written as 64 hex digits".to_string(),
+                ));
+            }
+            None if self.environment == Environment::Production => {
+                return Err(ConfigError::Invalid(
+                    "totp key must be set in production, either as AUTH_TOTP_KEY or `auth.totp_key`"
+                        .to_string(),
+                ));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// An absolute URL for `path`, which starts with a `/`.
+    pub fn public_url(&self, path: &str) -> String {
+        format!("{}{}", self.server.public_url.trim_end_matches('/'), path)
+    }
+
+    pub fn session_secure(&self) -> bool {
+        self.session
+            .secure
+            .unwrap_or(self.environment == Environment::Production)
+    }
+}
+
+fn read_file(path: &str) -> Result<String, ConfigError> {
+    fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_string(),
+        source,
+    })
+}
+
+fn parse_env<T>(name: &'static str, value: String) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse().map_err(|err: T::Err| ConfigError::Env {
+        name,
+        reason: err.to_string(),
+        value,
+    })
+}
+
+fn override_from_env<T>(
+    env: &impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    target: &mut T,
+) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env(name) {
+        *target = parse_env(name, value)?;
+    }
+
+    Ok(())
+}
+
+fn override_option_from_env<T>(
+    env: &impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    target: &mut Option<T>,
+) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env(name) {
+        *target = Some(parse_env(name, value)?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        move |name| vars.get(name).cloned()
+    }
+
+    fn file(contents: &str) -> Option<(String, String)> {
+        Some(("config.toml".to_string(), contents.to_string()))
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let config = Config::from_sources(
+            file("[server]\nport = 8000\n[database]\nurl = \"postgres://file\"\npool_size = 2"),
+            env(&[
+                ("APP_PORT", "9000"),
+                ("APP_ENVIRONMENT", "production"),
+                ("AUTH_TOTP_KEY", &"ab".repeat(32)),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.database.url, "postgres://file");
+        assert_eq!(config.database.pool_size, 2);
+        assert_eq!(config.environment, Environment::Production);
+        assert!(config.session_secure());
+    }
+
+    #[test]
+    fn invalid_environment_variable_is_reported() {
+        let err = Config::from_sources(
+            None,
+            env(&[("DATABASE_URL", "postgres://env"), ("APP_PORT", "eighty")]),
+        )
+        .unwrap_err();
+
+        assert!(matches!(err, ConfigError::Env { name: "APP_PORT", .. }));
+    }
+
+    #[test]
+    fn database_url_is_required() {
+        let err = Config::from_sources(None, env(&[])).unwrap_err();
+
+        assert!(matches!(err, ConfigError::Invalid(_)));
+    }
+
+    #[test]
+    fn unknown_file_keys_and_bad_identifiers_are_rejected() {
+        let err = Config::from_sources(file("[server]\nprot = 80"), env(&[])).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }));
+
+        let err = Config::from_sources(
+            None,
+            env(&[("DATABASE_URL", "postgres://env"), ("SESSION_TABLE_NAME", "a-b")]),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+    }
+
+    #[test]
+    fn totp_key_must_decode_and_is_required_in_production() {
+        let config = Config::from_sources(None, env(&[("DATABASE_URL", "postgres://env")])).unwrap();
+        assert_eq!(config.auth.totp_key(), DEVELOPMENT_TOTP_KEY);
+
+        let config = Config::from_sources(
+            None,
+            env(&[("DATABASE_URL", "postgres://env"), ("AUTH_TOTP_KEY", &"0F".repeat(32))]),
+        )
+        .unwrap();
+        assert_eq!(config.auth.totp_key(), [0x0f; 32]);
+
+        for vars in [
+            [("AUTH_TOTP_KEY", "0f0f"), ("APP_ENVIRONMENT", "development")],
+            [("AUTH_TOTP_KEY", ""), ("APP_ENVIRONMENT", "development")],
+            [("DATABASE_POOL_SIZE", "5"), ("APP_ENVIRONMENT", "production")],
+        ] {
+            let err = Config::from_sources(
+                None,
+                env(&[("DATABASE_URL", "postgres://env"), vars[0], vars[1]]),
+            )
+            .unwrap_err();
+            assert!(matches!(err, ConfigError::Invalid(_)));
+        }
+    }
+}
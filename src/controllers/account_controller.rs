@@ -0,0 +1,465 @@
+use crate::client_info::RequestClient;
+use crate::controllers::html_response::HtmlResponse;
+use crate::controllers::verification_controller;
+use crate::errors::AppError;
+use crate::impersonation::NotImpersonating;
+use crate::models::audit_event::AuditAction;
+use crate::models::feed_event::FeedEventKind;
+use crate::models::session::SessionModel;
+use crate::repositories::audit_repository::NewAuditEventDb;
+use crate::repositories::auth_backend::{AuthSession, Backend};
+use crate::repositories::export_repository::{self, SessionExport, UserExport};
+use crate::repositories::feed_repository;
+use crate::repositories::user_repository::{
+    self, ChangeEmailForm, ChangePasswordForm, DeleteAccountForm, ProfileForm, UserDb,
+};
+use crate::validation::ValidationErrors;
+use askama::Template;
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Form, Router};
+use axum_login::login_required;
+use diesel::Connection;
+use serde_json::json;
+use tower_sessions::Session;
+
+use crate::AppState;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/account", get(self::get::account))
+        .route("/account/profile", post(self::post::profile))
+        .route("/account/email", post(self::post::email))
+        .route("/account/password", post(self::post::password))
+        .route("/account/export.json", get(self::get::export_json))
+        .route("/account/export.zip", get(self::get::export_zip))
+        .route(
+            "/account/delete",
+            get(self::get::delete_account).post(self::post::delete_account),
+        )
+        .route("/account/sessions", get(self::get::sessions))
+        .route("/account/sessions/:handle", delete(self::delete::session))
+        .route_layer(login_required!(Backend, login_url = "/login"))
+}
+
+/// The account page with its three forms, each of which submits on its own.
+#[derive(Template)]
+#[template(path = "account.html")]
+struct AccountTemplate {
+    name: String,
+    email: String,
+    email_verified: bool,
+    errors: ValidationErrors,
+    notice: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "account_profile_form.html")]
+struct ProfileFormTemplate {
+    name: String,
+    errors: ValidationErrors,
+    notice: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "account_email_form.html")]
+struct EmailFormTemplate {
+    email: String,
+    email_verified: bool,
+    errors: ValidationErrors,
+    notice: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "account_password_form.html")]
+struct PasswordFormTemplate {
+    errors: ValidationErrors,
+    notice: Option<String>,
+}
+
+/// Asks for the password before deleting the account.
+#[derive(Template)]
+#[template(path = "account_delete.html")]
+struct DeleteAccountTemplate {
+    grace_days: i64,
+    errors: ValidationErrors,
+}
+
+/// Shown once logged out, the account can still be restored until `purge_on`.
+#[derive(Template)]
+#[template(path = "account_deleted.html")]
+struct AccountDeletedTemplate {
+    purge_on: chrono::NaiveDate,
+}
+
+/// Everything about the user, with the sessions from the session store.
+async fn export(state: &AppState, user_id: uuid::Uuid) -> Result<UserExport, AppError> {
+    let mut connection = state.pool.get()?;
+    let mut export = tokio::task::spawn_blocking(move || {
+        export_repository::export_user(&mut connection, user_id)
+    })
+    .await??;
+
+    let sessions = state.session_store.get_by_user_id(user_id).await?;
+    export.sessions = sessions.iter().map(SessionExport::from).collect();
+
+    Ok(export)
+}
+
+/// Sends `body` as a file to save rather than a page to show.
+fn download(content_type: &'static str, extension: &str, body: Vec<u8>) -> Response {
+    let filename = format!(
+        "informator-export-{}.{}",
+        chrono::Utc::now().format("%Y-%m-%d"),
+        extension
+    );
+    let disposition = format!("attachment; filename=\"{}\"", filename);
+
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Adds an error to the `current_password` field unless the password is right.
+/// Returns the status to respond with then.
+async fn check_current_password(
+    auth_session: &AuthSession,
+    user: &UserDb,
+    password: String,
+    errors: &mut ValidationErrors,
+) -> Result<Option<StatusCode>, AppError> {
+    match auth_session.backend.verify_current_password(user, password).await {
+        Ok(true) => Ok(None),
+        Ok(false) => {
+            errors.add("current_password", "That isn't your current password.");
+            Ok(Some(StatusCode::UNPROCESSABLE_ENTITY))
+        }
+        Err(err @ AppError::TooManyAttempts { .. }) => {
+            errors.add("current_password", err.user_message());
+            Ok(Some(err.status_code()))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+mod get {
+    use super::*;
+
+    pub async fn account(auth_session: AuthSession) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+
+        let template = AccountTemplate {
+            name: user.name,
+            email: user.email,
+            email_verified: user.email_verified_at.is_some(),
+            errors: ValidationErrors::default(),
+            notice: None,
+        };
+
+        Ok(HtmlResponse(template))
+    }
+
+    pub async fn export_json(
+        _: NotImpersonating,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+        let export = export(&state, user.id).await?;
+        let event = NewAuditEventDb::new(AuditAction::DataExported, &client)
+            .user(user.id)
+            .metadata(json!({ "format": "json" }));
+        auth_session.backend.audit(event).await?;
+
+        Ok(download("application/json", "json", export.to_json()))
+    }
+
+    pub async fn export_zip(
+        _: NotImpersonating,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+        let export = export(&state, user.id).await?;
+        let event = NewAuditEventDb::new(AuditAction::DataExported, &client)
+            .user(user.id)
+            .metadata(json!({ "format": "zip" }));
+        auth_session.backend.audit(event).await?;
+
+        Ok(download("application/zip", "zip", export.to_zip()?))
+    }
+
+    pub async fn delete_account(State(state): State<AppState>) -> impl IntoResponse {
+        HtmlResponse(DeleteAccountTemplate {
+            grace_days: state.config.auth.deletion_grace_days,
+            errors: ValidationErrors::default(),
+        })
+    }
+
+    #[derive(Template)]
+    #[template(path = "account_sessions.html")]
+    struct SessionsTemplate {
+        pub sessions: Vec<SessionModel>,
+    }
+
+    pub async fn sessions(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        session: Session,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+
+        let current_id = session.id().map(|id| id.to_string());
+        let sessions = state.session_store.get_by_user_id(user_id).await?;
+
+        let template = SessionsTemplate {
+            sessions: sessions
+                .iter()
+                .map(|session| session.to_model(current_id.as_deref()))
+                .collect(),
+        };
+
+        Ok(HtmlResponse(template))
+    }
+}
+
+mod post {
+    use super::*;
+
+    pub async fn profile(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Form(form): Form<ProfileForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+
+        let submitted = form.name.clone();
+        let name = match form.validate() {
+            Ok(name) => name,
+            Err(errors) => {
+                let template = ProfileFormTemplate {
+                    name: submitted,
+                    errors,
+                    notice: None,
+                };
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, HtmlResponse(template)).into_response());
+            }
+        };
+
+        if name != user.name {
+            let mut connection = state.pool.get()?;
+            let new_name = name.clone();
+            tokio::task::spawn_blocking(move || {
+                connection.transaction(|connection| {
+                    user_repository::update_name(connection, user.id, new_name)?;
+                    feed_repository::create_event(
+                        connection,
+                        user.id,
+                        FeedEventKind::ProfileUpdated,
+                        String::new(),
+                    )?;
+
+                    Ok::<_, diesel::result::Error>(())
+                })
+            })
+            .await??;
+        }
+
+        let template = ProfileFormTemplate {
+            name,
+            errors: ValidationErrors::default(),
+            notice: Some("Your profile is saved.".to_string()),
+        };
+
+        Ok(HtmlResponse(template).into_response())
+    }
+
+    /// Sends a link to the new email that switches the account to it, the
+    /// current email stays until the link is followed.
+    pub async fn email(
+        _: NotImpersonating,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Form(form): Form<ChangeEmailForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.clone().ok_or(AppError::Unauthorized)?;
+        let invalid = |errors, status| {
+            let template = EmailFormTemplate {
+                email: form.email.clone(),
+                email_verified: user.email_verified_at.is_some(),
+                errors,
+                notice: None,
+            };
+            Ok((status, HtmlResponse(template)).into_response())
+        };
+
+        let email = match form.validate() {
+            Ok(email) => email,
+            Err(errors) => return invalid(errors, StatusCode::UNPROCESSABLE_ENTITY),
+        };
+        let mut errors = ValidationErrors::default();
+        if email.to_lowercase() == user.email.to_lowercase() {
+            errors.add("email", "That is your email already.");
+            return invalid(errors, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        let password = form.current_password.clone();
+        if let Some(status) = check_current_password(&auth_session, &user, password, &mut errors).await? {
+            return invalid(errors, status);
+        }
+
+        // Checked again when the link is followed, someone may register it
+        // in the meantime
+        let mut connection = state.pool.get()?;
+        let new_email = email.clone();
+        let taken = tokio::task::spawn_blocking(move || {
+            match user_repository::get_by_email(&mut connection, new_email) {
+                Ok(_) => Ok(true),
+                Err(diesel::result::Error::NotFound) => Ok(false),
+                Err(err) => Err(err),
+            }
+        })
+        .await??;
+        if taken {
+            errors.add("email", "An account with this email already exists.");
+            return invalid(errors, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        verification_controller::send_email_change_email(&state, &user, email.clone()).await?;
+
+        let template = EmailFormTemplate {
+            email: user.email.clone(),
+            email_verified: user.email_verified_at.is_some(),
+            errors,
+            notice: Some(format!(
+                "We sent a link to {}, your email changes once you follow it.",
+                email
+            )),
+        };
+
+        Ok(HtmlResponse(template).into_response())
+    }
+
+    /// The password hash is the session auth hash, so changing it ends every
+    /// other session. This one is logged in again with the new hash.
+    pub async fn password(
+        _: NotImpersonating,
+        client: RequestClient,
+        State(state): State<AppState>,
+        mut auth_session: AuthSession,
+        session: Session,
+        Form(form): Form<ChangePasswordForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.clone().ok_or(AppError::Unauthorized)?;
+        let invalid = |errors, status| {
+            let template = PasswordFormTemplate {
+                errors,
+                notice: None,
+            };
+            Ok((status, HtmlResponse(template)).into_response())
+        };
+
+        let mut errors = ValidationErrors::default();
+        user_repository::check_password(&form.password, &mut errors);
+        if !errors.is_empty() {
+            return invalid(errors, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        let password = form.current_password;
+        if let Some(status) = check_current_password(&auth_session, &user, password, &mut errors).await? {
+            return invalid(errors, status);
+        }
+
+        let mut connection = state.pool.get()?;
+        let updated = tokio::task::spawn_blocking(move || {
+            connection.transaction(|connection| {
+                user_repository::update_password(connection, user.id, form.password)?;
+                user_repository::get_by_id(connection, user.id)
+            })
+        })
+        .await??;
+
+        auth_session.login(&updated).await?;
+        // The old hash already invalidates them, this drops the rows
+        state
+            .session_store
+            .delete_by_user_id(updated.id, session.id())
+            .await?;
+        let event = NewAuditEventDb::new(AuditAction::PasswordChanged, &client).user(updated.id);
+        auth_session.backend.audit(event).await?;
+
+        let template = PasswordFormTemplate {
+            errors,
+            notice: Some("Your password is changed, your other devices are logged out.".to_string()),
+        };
+
+        Ok(HtmlResponse(template).into_response())
+    }
+
+    /// Deletes the account after the grace period, logging it out everywhere
+    /// right away. Logging in before the purge restores it.
+    pub async fn delete_account(
+        _: NotImpersonating,
+        client: RequestClient,
+        State(state): State<AppState>,
+        mut auth_session: AuthSession,
+        Form(form): Form<DeleteAccountForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.clone().ok_or(AppError::Unauthorized)?;
+        let grace_days = state.config.auth.deletion_grace_days;
+
+        let mut errors = ValidationErrors::default();
+        let password = form.current_password;
+        if let Some(status) = check_current_password(&auth_session, &user, password, &mut errors).await? {
+            let template = DeleteAccountTemplate { grace_days, errors };
+            return Ok((status, HtmlResponse(template)).into_response());
+        }
+
+        let mut connection = state.pool.get()?;
+        tokio::task::spawn_blocking(move || user_repository::mark_deleted(&mut connection, user.id))
+            .await??;
+
+        auth_session.logout().await?;
+        state.session_store.delete_by_user_id(user.id, None).await?;
+        let event = NewAuditEventDb::new(AuditAction::AccountDeleted, &client).user(user.id);
+        auth_session.backend.audit(event).await?;
+
+        let purge_on = (chrono::Utc::now() + chrono::Duration::days(grace_days)).date_naive();
+        Ok(HtmlResponse(AccountDeletedTemplate { purge_on }).into_response())
+    }
+}
+
+mod delete {
+    use tower_sessions::{session::Id, SessionStore};
+
+    use super::*;
+
+    /// Revokes one of the user's sessions. The emptied response removes its
+    /// row from the page.
+    pub async fn session(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(handle): Path<String>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+
+        // Only sessions of the current user can be revoked
+        let sessions = state.session_store.get_by_user_id(user_id).await?;
+        let session_id: Id = sessions
+            .iter()
+            .find(|session| session.handle() == handle)
+            .and_then(|session| session.id.parse().ok())
+            .ok_or(AppError::NotFound)?;
+
+        state.session_store.delete(&session_id).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,615 @@
+use crate::client_info::RequestClient;
+use crate::controllers::html_response::{redirect, HtmlResponse};
+use crate::controllers::password_reset_controller;
+use crate::errors::AppError;
+use crate::impersonation::{Impersonator, IMPERSONATOR_KEY};
+use crate::models::audit_event::{AuditAction, AuditEventModel};
+use crate::models::impersonation::ImpersonationModel;
+use crate::models::page::Page;
+use crate::models::permission::{AUDIT_VIEW, USERS_MANAGE, USERS_VIEW};
+use crate::models::user::UserModel;
+use crate::repositories::audit_repository::{self, AuditSearch, NewAuditEventDb};
+use crate::repositories::auth_backend::{AuthSession, Backend};
+use crate::repositories::user_repository::{self, UserSearch, UserSort};
+use crate::repositories::{impersonation_repository, role_repository};
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Router,
+};
+use axum_login::{login_required, permission_required, AuthnBackend, AuthzBackend};
+use log::info;
+use serde_json::json;
+use tower_sessions::Session;
+use uuid::Uuid;
+
+use crate::AppState;
+
+pub fn router() -> Router<AppState> {
+    let view = Router::new()
+        .route("/admin", get(self::get::admin))
+        .route("/admin/users", get(self::get::users))
+        .route("/admin/users/:id", get(self::get::user))
+        .route_layer(permission_required!(Backend, USERS_VIEW));
+    let manage = Router::new()
+        .route("/admin/users/:id/disable", post(self::post::disable))
+        .route("/admin/users/:id/enable", post(self::post::enable))
+        .route("/admin/users/:id/password-reset", post(self::post::password_reset))
+        .route(
+            "/admin/users/:id/roles/:role_id",
+            post(self::post::role).delete(self::delete::role),
+        )
+        .route("/admin/users/:id/impersonate", post(self::post::impersonate))
+        .route_layer(permission_required!(Backend, USERS_MANAGE));
+    let audit = Router::new()
+        .route("/admin/audit", get(self::get::audit))
+        .route_layer(permission_required!(Backend, AUDIT_VIEW));
+
+    Router::new()
+        .merge(view)
+        .merge(manage)
+        .merge(audit)
+        // Logged in users without the permission get a 403, the others log in first
+        .route_layer(login_required!(Backend, login_url = "/login"))
+        // Used while logged in as someone else, who usually isn't an admin
+        .route("/impersonation", get(self::get::impersonation))
+        .route("/impersonation/stop", post(self::post::stop_impersonation))
+}
+
+/// How many users the admin table shows at once.
+const USERS_PER_PAGE: i64 = 25;
+/// How many events the audit log shows at once.
+const AUDIT_EVENTS_PER_PAGE: i64 = 50;
+/// How many of the latest impersonations the user page lists.
+const IMPERSONATION_HISTORY_LENGTH: i64 = 10;
+
+/// One page of the admin users table, with the links to sort and page it.
+struct UsersTable {
+    users: Page<UserModel>,
+    search: UserSearch,
+}
+
+impl UsersTable {
+    fn page(&self) -> i64 {
+        self.users.number.unwrap_or(1)
+    }
+
+    /// The same filters, sorted and paged differently.
+    fn url(&self, sort: UserSort, page: i64) -> String {
+        let search = UserSearch {
+            sort,
+            page,
+            after: None,
+            ..self.search.clone()
+        };
+
+        format!(
+            "/admin/users?{}",
+            serde_urlencoded::to_string(&search).unwrap_or_default()
+        )
+    }
+
+    fn page_url(&self, page: i64) -> String {
+        self.url(self.search.sort, page)
+    }
+
+    /// Sorting by the column it is sorted by already flips the direction.
+    fn sort_url(&self, column: &str) -> String {
+        let (ascending, descending) = match column {
+            "name" => (UserSort::NameAsc, UserSort::NameDesc),
+            "email" => (UserSort::EmailAsc, UserSort::EmailDesc),
+            _ => (UserSort::CreatedAsc, UserSort::CreatedDesc),
+        };
+        let sort = match self.search.sort == ascending {
+            true => descending,
+            false => ascending,
+        };
+
+        self.url(sort, 1)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_users.html")]
+struct UsersTemplate {
+    table: UsersTable,
+    /// The role names to filter by.
+    roles: Vec<String>,
+}
+
+/// The table alone, swapped in by searching, sorting and paging.
+#[derive(Template)]
+#[template(path = "admin_users_table.html")]
+struct UsersTableTemplate {
+    table: UsersTable,
+}
+
+/// A role the user may or may not have.
+struct RoleOption {
+    id: Uuid,
+    name: String,
+    assigned: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_user.html")]
+struct UserTemplate {
+    user: UserModel,
+    roles: Vec<RoleOption>,
+    impersonations: Vec<ImpersonationModel>,
+    /// Whether the current admin may change the user.
+    can_manage: bool,
+    is_self: bool,
+}
+
+/// One page of the audit log, with the links to page it.
+struct AuditTable {
+    events: Page<AuditEventModel>,
+    search: AuditSearch,
+}
+
+impl AuditTable {
+    fn page(&self) -> i64 {
+        self.events.number.unwrap_or(1)
+    }
+
+    /// The same filters on another page.
+    fn page_url(&self, page: i64) -> String {
+        let search = AuditSearch {
+            page,
+            ..self.search.clone()
+        };
+
+        format!(
+            "/admin/audit?{}",
+            serde_urlencoded::to_string(&search).unwrap_or_default()
+        )
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_audit.html")]
+struct AuditTemplate {
+    table: AuditTable,
+    actions: &'static [AuditAction],
+}
+
+/// The table alone, swapped in by filtering and paging.
+#[derive(Template)]
+#[template(path = "admin_audit_table.html")]
+struct AuditTableTemplate {
+    table: AuditTable,
+}
+
+#[derive(Template)]
+#[template(path = "impersonation_banner.html")]
+struct ImpersonationBannerTemplate {
+    name: String,
+    email: String,
+}
+
+fn user_url(user_id: Uuid) -> String {
+    format!("/admin/users/{}", user_id)
+}
+
+/// Search requests only swap the table, but htmx asks for the whole page
+/// again when restoring history it no longer has cached.
+fn wants_table(headers: &HeaderMap) -> bool {
+    headers.contains_key("HX-Request") && !headers.contains_key("HX-History-Restore-Request")
+}
+
+/// Admins can't act on themselves this way, they could lock themselves out.
+fn forbid_self(auth_session: &AuthSession, user_id: Uuid) -> Result<(), AppError> {
+    match auth_session.user.as_ref().map(|user| user.id) {
+        Some(current_id) if current_id == user_id => Err(AppError::BadRequest(
+            "You can't do that to your own account.".to_string(),
+        )),
+        Some(_) => Ok(()),
+        None => Err(AppError::Unauthorized),
+    }
+}
+
+/// An event of the current admin acting on the user.
+fn admin_event(
+    auth_session: &AuthSession,
+    client: &RequestClient,
+    action: AuditAction,
+    user_id: Uuid,
+) -> Result<NewAuditEventDb, AppError> {
+    let admin_id = auth_session.user.as_ref().ok_or(AppError::Unauthorized)?.id;
+
+    Ok(NewAuditEventDb::new(action, client).actor(admin_id).target(user_id))
+}
+
+mod get {
+    use super::*;
+
+    pub async fn admin() -> impl IntoResponse {
+        Redirect::to("/admin/users")
+    }
+
+    pub async fn users(
+        headers: HeaderMap,
+        State(state): State<AppState>,
+        Query(search): Query<UserSearch>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let mut connection = state.pool.get()?;
+
+        let query = search.clone();
+        let (users, roles) = tokio::task::spawn_blocking(move || {
+            let users = user_repository::get_users(&mut connection, &query, USERS_PER_PAGE)?;
+            let roles = role_repository::get_roles(&mut connection)?;
+
+            Ok::<_, diesel::result::Error>((users, roles))
+        })
+        .await??;
+
+        let table = UsersTable { users, search };
+
+        match wants_table(&headers) {
+            true => Ok(HtmlResponse(UsersTableTemplate { table }).into_response()),
+            false => {
+                let roles = roles.into_iter().map(|role| role.name).collect();
+                Ok(HtmlResponse(UsersTemplate { table, roles }).into_response())
+            }
+        }
+    }
+
+    pub async fn user(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(user_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let current = auth_session.user.as_ref().ok_or(AppError::Unauthorized)?;
+        let can_manage = auth_session
+            .backend
+            .has_perm(current, USERS_MANAGE.into())
+            .await?;
+        let is_self = current.id == user_id;
+
+        let mut connection = state.pool.get()?;
+        let (user, roles, assigned, impersonations) = tokio::task::spawn_blocking(move || {
+            let user = user_repository::get_by_id(&mut connection, user_id)?;
+            let roles = role_repository::get_roles(&mut connection)?;
+            let assigned = role_repository::get_by_user_id(&mut connection, user_id)?;
+            let impersonations = impersonation_repository::get_by_user_id(
+                &mut connection,
+                user_id,
+                IMPERSONATION_HISTORY_LENGTH,
+            )?;
+
+            Ok::<_, diesel::result::Error>((user, roles, assigned, impersonations))
+        })
+        .await??;
+
+        let template = UserTemplate {
+            user: user.to_model(),
+            roles: roles
+                .into_iter()
+                .map(|role| RoleOption {
+                    assigned: assigned.iter().any(|assigned| assigned.id == role.id),
+                    id: role.id,
+                    name: role.name,
+                })
+                .collect(),
+            impersonations: impersonations
+                .iter()
+                .map(|(impersonation, admin_name)| impersonation.to_model(admin_name))
+                .collect(),
+            can_manage,
+            is_self,
+        };
+
+        Ok(HtmlResponse(template))
+    }
+
+    pub async fn audit(
+        headers: HeaderMap,
+        State(state): State<AppState>,
+        Query(search): Query<AuditSearch>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let mut connection = state.pool.get()?;
+
+        let query = search.clone();
+        let events = tokio::task::spawn_blocking(move || {
+            audit_repository::get_events(&mut connection, &query, AUDIT_EVENTS_PER_PAGE)
+        })
+        .await??;
+
+        let table = AuditTable { events, search };
+
+        match wants_table(&headers) {
+            true => Ok(HtmlResponse(AuditTableTemplate { table }).into_response()),
+            false => {
+                let template = AuditTemplate {
+                    table,
+                    actions: &AuditAction::ALL,
+                };
+                Ok(HtmlResponse(template).into_response())
+            }
+        }
+    }
+
+    /// The banner every page loads, empty unless an admin is logged in as
+    /// someone else.
+    pub async fn impersonation(
+        auth_session: AuthSession,
+        session: Session,
+    ) -> Result<impl IntoResponse, AppError> {
+        let impersonator = session.get::<Impersonator>(IMPERSONATOR_KEY).await?;
+
+        match (impersonator, auth_session.user) {
+            (Some(_), Some(user)) => Ok(HtmlResponse(ImpersonationBannerTemplate {
+                name: user.name,
+                email: user.email,
+            })
+            .into_response()),
+            _ => Ok(().into_response()),
+        }
+    }
+}
+
+mod post {
+    use super::*;
+
+    pub async fn disable(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(user_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        forbid_self(&auth_session, user_id)?;
+        let event = admin_event(&auth_session, &client, AuditAction::UserDisabled, user_id)?;
+
+        let mut connection = state.pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            user_repository::set_disabled(&mut connection, user_id, true)?;
+            audit_repository::record(&mut connection, event)
+        })
+        .await??;
+
+        let deleted = state.session_store.delete_by_user_id(user_id, None).await?;
+        info!("Disabled user {}, deleting {} sessions", user_id, deleted);
+
+        Ok(redirect(&headers, &user_url(user_id)))
+    }
+
+    pub async fn enable(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(user_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let event = admin_event(&auth_session, &client, AuditAction::UserEnabled, user_id)?;
+
+        let mut connection = state.pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            user_repository::set_disabled(&mut connection, user_id, false)?;
+            audit_repository::record(&mut connection, event)
+        })
+        .await??;
+
+        Ok(redirect(&headers, &user_url(user_id)))
+    }
+
+    /// Replaces the password with a random one nobody knows and emails the
+    /// user a link to choose a new one. Their sessions end with the old
+    /// password.
+    pub async fn password_reset(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(user_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        forbid_self(&auth_session, user_id)?;
+        let event = admin_event(&auth_session, &client, AuditAction::PasswordResetForced, user_id)?;
+
+        let mut connection = state.pool.get()?;
+        let user = tokio::task::spawn_blocking(move || {
+            user_repository::update_password(&mut connection, user_id, crate::tokens::generate())?;
+            audit_repository::record(&mut connection, event)?;
+            user_repository::get_by_id(&mut connection, user_id)
+        })
+        .await??;
+
+        state.session_store.delete_by_user_id(user_id, None).await?;
+        password_reset_controller::send_password_reset_email(&state, &user).await?;
+
+        Ok(redirect(&headers, &user_url(user_id)))
+    }
+
+    pub async fn role(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path((user_id, role_id)): Path<(Uuid, Uuid)>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let event = admin_event(&auth_session, &client, AuditAction::RoleGranted, user_id)?
+            .metadata(json!({ "role_id": role_id }));
+
+        let mut connection = state.pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            // Nothing to record when the user had the role already, or didn't
+            if role_repository::assign(&mut connection, user_id, role_id)? {
+                audit_repository::record(&mut connection, event)?;
+            }
+
+            Ok::<_, diesel::result::Error>(())
+        })
+        .await??;
+
+        Ok(redirect(&headers, &user_url(user_id)))
+    }
+
+    /// Logs the admin in as the user until they switch back. Admins can't be
+    /// impersonated, that would only hand out what the admin has already.
+    pub async fn impersonate(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        mut auth_session: AuthSession,
+        session: Session,
+        Path(user_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        forbid_self(&auth_session, user_id)?;
+        let admin_id = auth_session.user.as_ref().ok_or(AppError::Unauthorized)?.id;
+
+        let user = auth_session
+            .backend
+            .get_user(&user_id)
+            .await?
+            .ok_or(AppError::BadRequest(
+                "Disabled and deleted users can't be logged in as.".to_string(),
+            ))?;
+        if auth_session.backend.has_perm(&user, USERS_MANAGE.into()).await? {
+            return Err(AppError::BadRequest("Admins can't be logged in as.".to_string()));
+        }
+
+        let event = admin_event(&auth_session, &client, AuditAction::ImpersonationStarted, user_id)?;
+
+        let mut connection = state.pool.get()?;
+        let impersonation_id = tokio::task::spawn_blocking(move || {
+            let impersonation_id = impersonation_repository::start(&mut connection, admin_id, user_id)?;
+            let event = event.metadata(json!({ "impersonation_id": impersonation_id }));
+            audit_repository::record(&mut connection, event)?;
+
+            Ok::<_, diesel::result::Error>(impersonation_id)
+        })
+        .await??;
+        info!("Admin {} logged in as user {}", admin_id, user_id);
+
+        auth_session.login(&user).await?;
+        let impersonator = Impersonator {
+            admin_id,
+            impersonation_id,
+        };
+        session.insert(IMPERSONATOR_KEY, impersonator).await?;
+
+        Ok(redirect(&headers, "/"))
+    }
+
+    /// Logs the admin back in as themselves.
+    pub async fn stop_impersonation(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        mut auth_session: AuthSession,
+        session: Session,
+    ) -> Result<impl IntoResponse, AppError> {
+        let Some(impersonator) = session.remove::<Impersonator>(IMPERSONATOR_KEY).await? else {
+            return Ok(redirect(&headers, "/"));
+        };
+        let user_id = auth_session.user.as_ref().map(|user| user.id);
+
+        let mut event = NewAuditEventDb::new(AuditAction::ImpersonationEnded, &client)
+            .actor(impersonator.admin_id)
+            .metadata(json!({ "impersonation_id": impersonator.impersonation_id }));
+        if let Some(user_id) = user_id {
+            event = event.target(user_id);
+        }
+
+        let mut connection = state.pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            impersonation_repository::end(&mut connection, impersonator.impersonation_id)?;
+            audit_repository::record(&mut connection, event)
+        })
+        .await??;
+
+        // The admin may have been disabled in the meantime
+        let Some(admin) = auth_session.backend.get_user(&impersonator.admin_id).await? else {
+            auth_session.logout().await?;
+            return Ok(redirect(&headers, "/login"));
+        };
+        auth_session.login(&admin).await?;
+
+        let location = user_id.map(user_url).unwrap_or_else(|| "/admin/users".to_string());
+        Ok(redirect(&headers, &location))
+    }
+}
+
+mod delete {
+    use super::*;
+
+    pub async fn role(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path((user_id, role_id)): Path<(Uuid, Uuid)>,
+    ) -> Result<impl IntoResponse, AppError> {
+        forbid_self(&auth_session, user_id)?;
+        let event = admin_event(&auth_session, &client, AuditAction::RoleRevoked, user_id)?
+            .metadata(json!({ "role_id": role_id }));
+
+        let mut connection = state.pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            // Nothing to record when the user had the role already, or didn't
+            if role_repository::revoke(&mut connection, user_id, role_id)? {
+                audit_repository::record(&mut connection, event)?;
+            }
+
+            Ok::<_, diesel::result::Error>(())
+        })
+        .await??;
+
+        Ok(redirect(&headers, &user_url(user_id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(search: UserSearch) -> UsersTable {
+        UsersTable {
+            users: Page {
+                items: Vec::new(),
+                total: 0,
+                per_page: USERS_PER_PAGE,
+                number: Some(search.page),
+                next_cursor: None,
+            },
+            search,
+        }
+    }
+
+    #[test]
+    fn sort_links_keep_the_filters_and_flip_the_direction() {
+        let table = table(UserSearch {
+            q: "sam & co".to_string(),
+            role: "admin".to_string(),
+            created_from: chrono::NaiveDate::from_ymd_opt(2024, 1, 31),
+            sort: UserSort::NameAsc,
+            page: 3,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            table.sort_url("name"),
+            "/admin/users?q=sam+%26+co&role=admin&created_from=2024-01-31&sort=-name&page=1"
+        );
+        assert_eq!(
+            table.page_url(4),
+            "/admin/users?q=sam+%26+co&role=admin&created_from=2024-01-31&sort=name&page=4"
+        );
+        assert_eq!(table.page(), 3);
+    }
+
+    #[test]
+    fn empty_filter_inputs_are_ignored() {
+        let search: UserSearch =
+            serde_urlencoded::from_str("q=&role=&created_from=&created_to=2024-02-01&after=")
+                .unwrap();
+
+        assert_eq!(search.created_from, None);
+        assert_eq!(search.created_to, chrono::NaiveDate::from_ymd_opt(2024, 2, 1));
+        assert_eq!(search.after, None);
+    }
+}
@@ -1,101 +1,385 @@
-use crate::controllers::html_response::HtmlResponse;
+use crate::client_info::RequestClient;
+use crate::controllers::html_response::{redirect, HtmlResponse};
+use crate::controllers::verification_controller;
+use crate::errors::AppError;
+use crate::models::audit_event::AuditAction;
+use crate::repositories::audit_repository::NewAuditEventDb;
 use crate::repositories::user_repository;
+use crate::validation::ValidationErrors;
 use askama::Template;
 use axum::{
-    extract::State,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Redirect},
     routing::{get, post},
     Router,
 };
-use diesel::connection;
 
-use crate::db::schema::users;
 use crate::AppState;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tower_sessions::Session;
+use uuid::Uuid;
 
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/login", get(self::get::login))
         .route("/login", post(self::post::login))
+        .route(
+            "/login/two-factor",
+            get(self::get::two_factor).post(self::post::two_factor),
+        )
         .route("/register", get(self::get::register))
         .route("/register", post(self::post::register))
+        .route("/logout", post(self::post::logout))
+        .route("/logout/others", post(self::post::logout_others))
 }
 
 #[derive(Template)]
 #[template(path = "login.html")]
-struct LoginTemplate {}
+struct LoginTemplate {
+    error: Option<String>,
+    locked: bool,
+    email: String,
+    next: Option<String>,
+}
+
+/// The login form alone, swapped back in when the credentials are wrong.
+#[derive(Template)]
+#[template(path = "login_form.html")]
+struct LoginFormTemplate {
+    error: Option<String>,
+    /// Offer the unlock link along with the error.
+    locked: bool,
+    email: String,
+    next: Option<String>,
+}
+
+/// Where `login_required!` wants the user to go back to after logging in.
+#[derive(Deserialize)]
+pub struct NextQuery {
+    next: Option<String>,
+}
+
+/// Only same-origin relative paths are followed after login, anything else
+/// could send the user to another site.
+fn safe_next(next: Option<&str>) -> Option<&str> {
+    next.filter(|next| {
+        next.starts_with('/')
+            && !next.starts_with("//")
+            && !next.contains('\\')
+            && !next.chars().any(char::is_control)
+    })
+}
+
+/// Kept in the session between the password and the code step of a login
+/// with two-factor authentication. Nobody is logged in until the code checks
+/// out.
+const PENDING_TWO_FACTOR_KEY: &str = "informator.pending_two_factor";
+/// How long the code step waits for a code before the password is asked again.
+const PENDING_TWO_FACTOR_TTL_SECS: i64 = 5 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct PendingTwoFactor {
+    user_id: Uuid,
+    next: Option<String>,
+    expires_at: i64,
+}
+
+/// The pending login of the session, unless it expired.
+async fn pending_two_factor(session: &Session) -> Result<Option<PendingTwoFactor>, AppError> {
+    let pending = session.get::<PendingTwoFactor>(PENDING_TWO_FACTOR_KEY).await?;
+
+    Ok(pending.filter(|pending| pending.expires_at > chrono::Utc::now().timestamp()))
+}
+
+#[derive(Template)]
+#[template(path = "login_two_factor.html")]
+struct LoginTwoFactorTemplate {
+    error: Option<String>,
+    locked: bool,
+}
+
+/// The code form alone, swapped back in when the code is wrong.
+#[derive(Template)]
+#[template(path = "login_two_factor_form.html")]
+struct LoginTwoFactorFormTemplate {
+    error: Option<String>,
+    /// Offer the unlock link along with the error.
+    locked: bool,
+}
 
 #[derive(Template)]
 #[template(path = "register.html")]
-struct RegisterTemplate {}
+struct RegisterTemplate {
+    name: String,
+    email: String,
+    errors: ValidationErrors,
+}
+
+/// The registration form alone, swapped back in with the field errors.
+#[derive(Template)]
+#[template(path = "register_form.html")]
+struct RegisterFormTemplate {
+    name: String,
+    email: String,
+    errors: ValidationErrors,
+}
 
 mod get {
     use super::*;
 
-    pub async fn login(State(state): State<AppState>) -> impl IntoResponse {
-        let template = LoginTemplate {};
+    pub async fn login(Query(query): Query<NextQuery>) -> impl IntoResponse {
+        let template = LoginTemplate {
+            error: None,
+            locked: false,
+            email: String::new(),
+            next: safe_next(query.next.as_deref()).map(str::to_string),
+        };
 
-        return HtmlResponse(template);
+        HtmlResponse(template)
     }
 
-    pub async fn register(State(state): State<AppState>) -> impl IntoResponse {
-        let template = RegisterTemplate {};
+    /// Asks for the code of a login that got past the password, anyone else
+    /// starts at the password.
+    pub async fn two_factor(session: Session) -> Result<impl IntoResponse, AppError> {
+        if pending_two_factor(&session).await?.is_none() {
+            return Ok(Redirect::to("/login").into_response());
+        }
+
+        let template = LoginTwoFactorTemplate {
+            error: None,
+            locked: false,
+        };
 
-        return HtmlResponse(template);
+        Ok(HtmlResponse(template).into_response())
+    }
+
+    pub async fn register() -> impl IntoResponse {
+        let template = RegisterTemplate {
+            name: String::new(),
+            email: String::new(),
+            errors: ValidationErrors::default(),
+        };
+
+        HtmlResponse(template)
     }
 }
 
 mod post {
     use crate::repositories::{
         auth_backend::AuthSession,
-        user_repository::{Credentials, NewUserDb},
+        two_factor_repository::TwoFactorCodeForm,
+        user_repository::{Credentials, RegisterForm},
     };
-    use axum::{http::StatusCode, Form};
+    use axum::Form;
+    use diesel::result::DatabaseErrorKind;
 
     use super::*;
+
     pub async fn login(
+        headers: HeaderMap,
+        client: RequestClient,
         mut auth_session: AuthSession,
-        Form(creds): Form<Credentials>,
-    ) -> impl IntoResponse {
-        println!("Creds: {:?}", creds);
-
-        let user = match auth_session.authenticate(creds.clone()).await {
-            Ok(Some(user)) => user,
-            Ok(None) => {
-                return HtmlResponse(LoginTemplate {}).into_response();
+        session: Session,
+        Form(mut creds): Form<Credentials>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let next = safe_next(creds.next.as_deref()).map(str::to_string);
+        creds.ip_address = client.ip_address.clone();
+        creds.user_agent = client.user_agent.clone();
+
+        // Both failures read the same whether or not the email has an account
+        let (status, error, locked) = match auth_session.authenticate(creds.clone()).await {
+            // The password alone isn't enough, the code step logs them in
+            Ok(Some(user)) if user.totp_enabled_at.is_some() => {
+                let pending = PendingTwoFactor {
+                    user_id: user.id,
+                    next,
+                    expires_at: chrono::Utc::now().timestamp() + PENDING_TWO_FACTOR_TTL_SECS,
+                };
+                session.insert(PENDING_TWO_FACTOR_KEY, pending).await?;
+
+                return Ok(redirect(&headers, "/login/two-factor"));
             }
-            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Ok(Some(mut user)) => {
+                auth_session.backend.restore_if_deleted(&mut user, &client).await?;
+                auth_session.login(&user).await?;
+                let event = NewAuditEventDb::new(AuditAction::LoginSucceeded, &client)
+                    .user(user.id)
+                    .metadata(json!({ "two_factor": false }));
+                auth_session.backend.audit(event).await?;
+
+                return Ok(redirect(&headers, next.as_deref().unwrap_or("/")));
+            }
+            Ok(None) => (StatusCode::UNAUTHORIZED, "Invalid email or password.".to_string(), false),
+            Err(axum_login::Error::Backend(err @ AppError::TooManyAttempts { .. })) => {
+                (err.status_code(), err.user_message(), true)
+            }
+            Err(err) => return Err(err.into()),
         };
 
-        if auth_session.login(&user).await.is_err() {
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
+        let template = LoginFormTemplate {
+            error: Some(error),
+            locked,
+            email: creds.email,
+            next,
+        };
 
-        if let Some(ref next) = creds.next {
-            Redirect::to(next).into_response()
-        } else {
-            Redirect::to("/").into_response()
-        }
+        Ok((status, HtmlResponse(template)).into_response())
+    }
+
+    /// Logs in the user of the pending login once their code checks out.
+    pub async fn two_factor(
+        headers: HeaderMap,
+        client: RequestClient,
+        mut auth_session: AuthSession,
+        session: Session,
+        Form(form): Form<TwoFactorCodeForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let Some(pending) = pending_two_factor(&session).await? else {
+            return Ok(redirect(&headers, "/login"));
+        };
+        let Some(mut user) = auth_session.backend.get_pending_user(pending.user_id).await? else {
+            session.remove_value(PENDING_TWO_FACTOR_KEY).await?;
+            return Ok(redirect(&headers, "/login"));
+        };
+
+        let verified = auth_session
+            .backend
+            .verify_second_factor(&user, form.code, client.ip_address.clone())
+            .await;
+        let (status, error, locked) = match verified {
+            Ok(true) => {
+                session.remove_value(PENDING_TWO_FACTOR_KEY).await?;
+                auth_session.backend.restore_if_deleted(&mut user, &client).await?;
+                auth_session.login(&user).await?;
+                let event = NewAuditEventDb::new(AuditAction::LoginSucceeded, &client)
+                    .user(user.id)
+                    .metadata(json!({ "two_factor": true }));
+                auth_session.backend.audit(event).await?;
+
+                return Ok(redirect(&headers, pending.next.as_deref().unwrap_or("/")));
+            }
+            Ok(false) => {
+                let event = NewAuditEventDb::new(AuditAction::TwoFactorFailed, &client).user(user.id);
+                auth_session.backend.audit(event).await?;
+
+                (StatusCode::UNAUTHORIZED, "That code didn't work.".to_string(), false)
+            }
+            Err(err @ AppError::TooManyAttempts { .. }) => {
+                (err.status_code(), err.user_message(), true)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let template = LoginTwoFactorFormTemplate {
+            error: Some(error),
+            locked,
+        };
+
+        Ok((status, HtmlResponse(template)).into_response())
     }
 
     pub async fn register(
+        headers: HeaderMap,
+        client: RequestClient,
         State(state): State<AppState>,
         mut auth_session: AuthSession,
-        Form(creds): Form<NewUserDb>,
-    ) -> impl IntoResponse {
-        println!("Creds: {:?}", creds);
-        let mut connection = state.pool.get().unwrap();
+        Form(form): Form<RegisterForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let invalid = |form: &RegisterForm, errors| {
+            let template = RegisterFormTemplate {
+                name: form.name.clone(),
+                email: form.email.clone(),
+                errors,
+            };
+
+            (StatusCode::UNPROCESSABLE_ENTITY, HtmlResponse(template)).into_response()
+        };
 
-        let user = tokio::task::spawn_blocking(move || {
-            user_repository::create_user(&mut connection, creds)
+        let new_user = match form.clone().validate() {
+            Ok(new_user) => new_user,
+            Err(errors) => return Ok(invalid(&form, errors)),
+        };
+
+        let mut connection = state.pool.get()?;
+        let result = tokio::task::spawn_blocking(move || {
+            user_repository::create_user(&mut connection, new_user)
         })
-        .await
-        .unwrap()
-        .unwrap();
+        .await?;
+
+        let user = match result {
+            Ok(user) => user,
+            // The unique index on lower(email) settles races between signups too
+            Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                let mut errors = ValidationErrors::default();
+                errors.add("email", "An account with this email already exists.");
+                return Ok(invalid(&form, errors));
+            }
+            Err(err) => return Err(err.into()),
+        };
 
-        if auth_session.login(&user).await.is_err() {
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        auth_session.login(&user).await?;
+        let event = NewAuditEventDb::new(AuditAction::Registered, &client).user(user.id);
+        auth_session.backend.audit(event).await?;
+        verification_controller::send_verification_email(&state, &user).await?;
+
+        Ok(redirect(&headers, "/"))
+    }
+
+    /// Ends the current session, deleting its row from the store.
+    pub async fn logout(
+        headers: HeaderMap,
+        client: RequestClient,
+        mut auth_session: AuthSession,
+    ) -> Result<impl IntoResponse, AppError> {
+        if let Some(user) = auth_session.logout().await? {
+            let event = NewAuditEventDb::new(AuditAction::Logout, &client).user(user.id);
+            auth_session.backend.audit(event).await?;
         }
 
-        Redirect::to("/").into_response()
+        Ok(redirect(&headers, "/login"))
+    }
+
+    /// Deletes every other session of the current user, keeping this one.
+    pub async fn logout_others(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        session: Session,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+
+        let deleted = state
+            .session_store
+            .delete_by_user_id(user.id, session.id())
+            .await?;
+        info!("Logged out {} other sessions of user {}", deleted, user.id);
+        let event = NewAuditEventDb::new(AuditAction::LogoutOthers, &client)
+            .user(user.id)
+            .metadata(json!({ "sessions": deleted }));
+        auth_session.backend.audit(event).await?;
+
+        Ok(redirect(&headers, "/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_must_be_a_relative_path() {
+        assert_eq!(safe_next(Some("/todos")), Some("/todos"));
+        assert_eq!(safe_next(Some("/feed?before=1")), Some("/feed?before=1"));
+        assert_eq!(safe_next(None), None);
+        assert_eq!(safe_next(Some("")), None);
+        assert_eq!(safe_next(Some("todos")), None);
+        assert_eq!(safe_next(Some("https://evil.example")), None);
+        assert_eq!(safe_next(Some("//evil.example")), None);
+        assert_eq!(safe_next(Some("/\\evil.example")), None);
+        assert_eq!(safe_next(Some("/\tevil.example")), None);
     }
 }
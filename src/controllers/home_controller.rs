@@ -1,71 +1,446 @@
 use crate::controllers::html_response::HtmlResponse;
-use crate::repositories::auth_backend::Backend;
-use crate::repositories::user_repository;
+use crate::controllers::verification_controller;
+use crate::repositories::auth_backend::{AuthSession, Backend};
+use crate::repositories::{feed_repository, todo_repository};
+use crate::models::feed_event::{FeedEventKind, FeedEventModel};
+use crate::models::todo::TodoModel;
 use askama::Template;
-use axum::extract::State;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
 use axum::response::IntoResponse;
-use axum::routing::get;
+use crate::errors::AppError;
+use crate::validation::ValidationErrors;
+use axum::routing::{get, post};
 use axum::Router;
-use axum_login::login_required;
+use axum_login::{login_required, AuthzBackend};
+use diesel::Connection;
+use serde::Deserialize;
+use uuid::Uuid;
 use crate::AppState;
 
-
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/", get(self::get::home))
         .route("/todos", get(self::get::todos))
+        .route("/todos", post(self::post::todos))
+        .route("/todos/reorder", post(self::post::reorder))
+        .route(
+            "/todos/:id",
+            get(self::get::todo)
+                .put(self::put::todo)
+                .patch(self::patch::todo)
+                .delete(self::delete::todo),
+        )
+        .route("/todos/:id/edit", get(self::get::edit_todo))
         .route("/feed", get(self::get::feed))
+        .route("/feed/entries", get(self::get::feed_entries))
+        .route_layer(axum::middleware::from_fn(
+            verification_controller::verified_email_required,
+        ))
         .route_layer(login_required!(Backend, login_url = "/login"))
 }
 
+#[derive(Template)]
+#[template(path = "todo_item.html")]
+struct TodoItemFragmentTemplate {
+    pub todo: TodoModel,
+}
+
+/// The add form alone, swapped back in with the errors.
+#[derive(Template)]
+#[template(path = "todo_form.html")]
+struct TodoFormFragmentTemplate {
+    pub description: String,
+    pub errors: ValidationErrors,
+}
+
+#[derive(Template)]
+#[template(path = "todo_edit.html")]
+struct TodoEditFragmentTemplate {
+    pub todo: TodoModel,
+    pub errors: ValidationErrors,
+}
+
+/// How many feed events are loaded per infinite scroll step.
+const FEED_PAGE_SIZE: i64 = 20;
+
+#[derive(Debug, Deserialize)]
+struct FeedQuery {
+    before: Option<Uuid>,
+}
+
 mod get {
+    use crate::models::permission::USERS_VIEW;
+    use crate::models::user::UserModel;
+
     use super::*;
 
     #[derive(Template)] // The derive(Template) macro generates the code needed to render your template.
     #[template(path = "home.html")] // This specifies the path to the template file.
-    struct HomeTemplate<'a> {
+    struct HomeTemplate {
         // This struct will hold the variables that you'll use in your template.
-        pub name: &'a str,
+        pub name: String,
+        /// Links to the admin pages.
+        pub is_admin: bool,
+        /// The empty add form.
+        pub description: String,
+        pub errors: ValidationErrors,
     }
-    
-    pub async fn home(State(state): State<AppState>) -> impl IntoResponse {
-        let template = HomeTemplate { name: "Samuel" };
-        let mut connection = state.pool.get().unwrap();
-    
-        let results = tokio::task::spawn_blocking(move || user_repository::get_users(&mut connection))
-            .await
-            .unwrap();
-    
-        if let Ok(user) = results {
-            println!("User: {:?}", user);
-        }
-    
-        return HtmlResponse(template);
+
+    pub async fn home(auth_session: AuthSession) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+        let is_admin = auth_session.backend.has_perm(&user, USERS_VIEW.into()).await?;
+
+        let template = HomeTemplate {
+            name: user.name,
+            is_admin,
+            description: String::new(),
+            errors: ValidationErrors::default(),
+        };
+
+        Ok(HtmlResponse(template))
     }
     
     #[derive(Template)]
     #[template(path = "todos.html")]
-    pub struct TodosFragmentTemplate {}
+    pub struct TodosFragmentTemplate {
+        pub todos: Vec<TodoModel>,
+    }
     
-    pub async fn todos() -> impl IntoResponse {
-        let template = TodosFragmentTemplate {};
+    pub async fn todos(State(state): State<AppState>, auth_session: AuthSession) -> Result<impl IntoResponse, AppError> {
+        // login_required! guarantees the user is present
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+        let mut connection = state.pool.get()?;
+
+        let todos = tokio::task::spawn_blocking(move || {
+            todo_repository::get_by_user_id(&mut connection, user_id)
+        })
+        .await??;
+
+        let template = TodosFragmentTemplate {
+            todos: todos.iter().map(|todo| todo.to_model()).collect(),
+        };
     
-        return HtmlResponse(template);
+        Ok(HtmlResponse(template))
+    }
+
+    pub async fn todo(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(todo_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+        let mut connection = state.pool.get()?;
+
+        let todo = tokio::task::spawn_blocking(move || {
+            todo_repository::get_by_id(&mut connection, todo_id, user_id)
+        })
+        .await??;
+
+        match todo {
+            Some(todo) => Ok(HtmlResponse(TodoItemFragmentTemplate {
+                todo: todo.to_model(),
+            })),
+            None => Err(AppError::NotFound),
+        }
+    }
+
+    pub async fn edit_todo(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(todo_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+        let mut connection = state.pool.get()?;
+
+        let todo = tokio::task::spawn_blocking(move || {
+            todo_repository::get_by_id(&mut connection, todo_id, user_id)
+        })
+        .await??;
+
+        match todo {
+            Some(todo) => Ok(HtmlResponse(TodoEditFragmentTemplate {
+                todo: todo.to_model(),
+                errors: ValidationErrors::default(),
+            })),
+            None => Err(AppError::NotFound),
+        }
     }
     
-    // template for feed
     #[derive(Template)] // The derive(Template) macro generates the code needed to render your template.
     #[template(path = "feed.html")] // This specifies the path to the template file.
-    struct FeedTemplate<'a> {
-        // TODO: add proper types from models
-        pub name: &'a str,
+    struct FeedTemplate {
+        pub user: UserModel,
+        pub events: Vec<FeedEventModel>,
+        pub next_cursor: Option<Uuid>,
     }
-    
-    pub async fn feed() -> impl IntoResponse {
-        let template = FeedTemplate { name: "Samuel" };
-    
-        return HtmlResponse(template);
+
+    #[derive(Template)]
+    #[template(path = "feed_entries.html")]
+    struct FeedEntriesFragmentTemplate {
+        pub events: Vec<FeedEventModel>,
+        pub next_cursor: Option<Uuid>,
+    }
+
+    /// Loads one page of the feed, returning its events and the cursor for the
+    /// next page, if any.
+    async fn load_feed_page(
+        state: AppState,
+        viewer_id: Uuid,
+        before: Option<Uuid>,
+    ) -> Result<(Vec<FeedEventModel>, Option<Uuid>), AppError> {
+        let mut connection = state.pool.get()?;
+
+        let (rows, has_more) = tokio::task::spawn_blocking(move || {
+            feed_repository::get_page(&mut connection, before, FEED_PAGE_SIZE)
+        })
+        .await??;
+
+        let next_cursor = match has_more {
+            true => rows.last().map(|(event, _)| event.id),
+            false => None,
+        };
+        let events = rows
+            .iter()
+            .filter_map(|(event, user)| event.to_model(user, viewer_id))
+            .collect();
+
+        Ok((events, next_cursor))
+    }
+
+    pub async fn feed(State(state): State<AppState>, auth_session: AuthSession) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?.to_model();
+        let (events, next_cursor) = load_feed_page(state, user.id, None).await?;
+
+        let template = FeedTemplate {
+            user,
+            events,
+            next_cursor,
+        };
+
+        Ok(HtmlResponse(template))
+    }
+
+    pub async fn feed_entries(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Query(query): Query<FeedQuery>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let viewer_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+        let (events, next_cursor) = load_feed_page(state, viewer_id, query.before).await?;
+
+        let template = FeedEntriesFragmentTemplate {
+            events,
+            next_cursor,
+        };
+
+        Ok(HtmlResponse(template))
+    }
+}
+
+mod post {
+    use crate::repositories::todo_repository::{NewTodoDb, TodoForm};
+    use axum::Form;
+
+    use super::*;
+
+    pub async fn todos(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Form(form): Form<TodoForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        // login_required! guarantees the user is present
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+        let description = match form.validate() {
+            Ok(description) => description,
+            Err(errors) => {
+                let template = TodoFormFragmentTemplate {
+                    description: form.description,
+                    errors,
+                };
+                // The button targets the list, the errors belong in the form
+                let retarget = [("HX-Retarget", "#add-form"), ("HX-Reswap", "outerHTML")];
+
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, retarget, HtmlResponse(template)).into_response());
+            }
+        };
+        let mut connection = state.pool.get()?;
+
+        let todo = tokio::task::spawn_blocking(move || {
+            connection.transaction(|connection| {
+                let todo = todo_repository::create_todo(
+                    connection,
+                    NewTodoDb {
+                        user_id,
+                        description,
+                    },
+                )?;
+                feed_repository::create_event(
+                    connection,
+                    user_id,
+                    FeedEventKind::TodoCreated,
+                    todo.description.clone(),
+                )?;
+
+                Ok::<_, diesel::result::Error>(todo)
+            })
+        })
+        .await??;
+
+        let template = TodoItemFragmentTemplate {
+            todo: todo.to_model(),
+        };
+
+        Ok(HtmlResponse(template).into_response())
+    }
+
+    pub async fn reorder(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Form(fields): Form<Vec<(String, String)>>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+
+        // The sortable form posts one `item` field per todo, in display order
+        let todo_ids = match fields
+            .into_iter()
+            .filter(|(name, _)| name == "item")
+            .map(|(_, value)| Uuid::parse_str(&value))
+            .collect::<Result<Vec<Uuid>, _>>()
+        {
+            Ok(todo_ids) => todo_ids,
+            Err(_) => return Err(AppError::BadRequest("Invalid todo order.".to_string())),
+        };
+
+        let mut connection = state.pool.get()?;
+
+        let todos = tokio::task::spawn_blocking(move || {
+            todo_repository::reorder(&mut connection, user_id, todo_ids)?;
+            todo_repository::get_by_user_id(&mut connection, user_id)
+        })
+        .await??;
+
+        let template = get::TodosFragmentTemplate {
+            todos: todos.iter().map(|todo| todo.to_model()).collect(),
+        };
+
+        Ok(HtmlResponse(template))
     }
 }
 
+mod put {
+    use crate::repositories::todo_repository::TodoForm;
+    use axum::Form;
+
+    use super::*;
+
+    pub async fn todo(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(todo_id): Path<Uuid>,
+        Form(form): Form<TodoForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+        let mut connection = state.pool.get()?;
+
+        let description = match form.validate() {
+            Ok(description) => description,
+            Err(errors) => {
+                // Still only the owner gets to see the form
+                let todo = tokio::task::spawn_blocking(move || {
+                    todo_repository::get_by_id(&mut connection, todo_id, user_id)
+                })
+                .await??
+                .ok_or(AppError::NotFound)?;
+                let template = TodoEditFragmentTemplate {
+                    todo: TodoModel {
+                        description: form.description,
+                        ..todo.to_model()
+                    },
+                    errors,
+                };
 
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, HtmlResponse(template)).into_response());
+            }
+        };
+
+        let todo = tokio::task::spawn_blocking(move || {
+            todo_repository::update_description(&mut connection, todo_id, user_id, description)
+        })
+        .await??;
+
+        match todo {
+            Some(todo) => Ok(HtmlResponse(TodoItemFragmentTemplate {
+                todo: todo.to_model(),
+            })
+            .into_response()),
+            None => Err(AppError::NotFound),
+        }
+    }
+}
+
+mod patch {
+    use super::*;
+
+    pub async fn todo(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(todo_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+        let mut connection = state.pool.get()?;
+
+        let todo = tokio::task::spawn_blocking(move || {
+            connection.transaction(|connection| {
+                let todo = todo_repository::toggle_done(connection, todo_id, user_id)?;
+
+                // Only completing a todo the first time is newsworthy, neither
+                // un-checking it nor completing it again is
+                if let Some(todo) = todo.as_ref().filter(|todo| todo.done) {
+                    if todo_repository::record_completion(connection, todo.id)? {
+                        feed_repository::create_event(
+                            connection,
+                            user_id,
+                            FeedEventKind::TodoCompleted,
+                            todo.description.clone(),
+                        )?;
+                    }
+                }
+
+                Ok::<_, diesel::result::Error>(todo)
+            })
+        })
+        .await??;
+
+        match todo {
+            Some(todo) => Ok(HtmlResponse(TodoItemFragmentTemplate {
+                todo: todo.to_model(),
+            })),
+            None => Err(AppError::NotFound),
+        }
+    }
+}
+
+mod delete {
+    use super::*;
+
+    pub async fn todo(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Path(todo_id): Path<Uuid>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user_id = auth_session.user.ok_or(AppError::Unauthorized)?.id;
+        let mut connection = state.pool.get()?;
+
+        let deleted = tokio::task::spawn_blocking(move || {
+            todo_repository::delete_by_id(&mut connection, todo_id, user_id)
+        })
+        .await??;
+
+        // htmx swaps the <li> with the empty body, removing it from the list
+        match deleted {
+            true => Ok(StatusCode::OK),
+            false => Err(AppError::NotFound),
+        }
+    }
+}
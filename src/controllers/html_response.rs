@@ -1,6 +1,7 @@
-use axum::response::{Html, IntoResponse, Response};
-use axum::http::StatusCode;
-use askama::Template; 
+use axum::http::HeaderMap;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use askama::Template;
+use crate::errors::AppError;
 
 /// A wrapper type that we'll use to encapsulate HTML parsed by askama into valid HTML for axum to serve.
 pub struct HtmlResponse<T>(pub T);
@@ -14,12 +15,17 @@ where
         match self.0.render() {
             // If we're able to successfully parse and aggregate the template, serve it
             Ok(html) => Html(html).into_response(),
-            // If we're not, return an error or some bit of fallback HTML
-            Err(err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to render template. Error: {}", err),
-            )
-                .into_response(),
+            // If we're not, let the error middleware render a proper error page
+            Err(err) => AppError::from(err).into_response(),
         }
     }
-}
\ No newline at end of file
+}
+
+/// htmx follows plain redirects itself and swaps the result into the page, so
+/// htmx requests are told to navigate with `HX-Redirect` instead.
+pub fn redirect(headers: &HeaderMap, location: &str) -> Response {
+    match headers.contains_key("HX-Request") {
+        true => [("HX-Redirect", location.to_string())].into_response(),
+        false => Redirect::to(location).into_response(),
+    }
+}
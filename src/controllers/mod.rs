@@ -1,3 +1,10 @@
 pub mod home_controller;
 pub mod auth_controller;
+pub mod service_controller;
+pub mod account_controller;
+pub mod password_reset_controller;
+pub mod verification_controller;
+pub mod unlock_controller;
+pub mod two_factor_controller;
+pub mod admin_controller;
 mod html_response;
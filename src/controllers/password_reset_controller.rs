@@ -0,0 +1,225 @@
+use crate::client_info::RequestClient;
+use crate::controllers::html_response::{redirect, HtmlResponse};
+use crate::errors::AppError;
+use crate::mailer::Email;
+use crate::models::audit_event::AuditAction;
+use crate::repositories::audit_repository::{self, NewAuditEventDb};
+use crate::repositories::login_throttle_repository;
+use crate::repositories::password_reset_repository::{
+    self, ForgotPasswordForm, ResetPasswordForm, TOKEN_TTL_MINUTES,
+};
+use crate::repositories::user_repository::{self, UserDb};
+use crate::validation::ValidationErrors;
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Form, Router,
+};
+use log::error;
+
+use crate::AppState;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/forgot-password",
+            get(self::get::forgot_password).post(self::post::forgot_password),
+        )
+        .route(
+            "/reset-password/:token",
+            get(self::get::reset_password).post(self::post::reset_password),
+        )
+}
+
+#[derive(Template)]
+#[template(path = "forgot_password.html")]
+struct ForgotPasswordTemplate {}
+
+/// Replaces the form once submitted. It reads the same whether or not the
+/// email has an account, so it can't be used to find out.
+#[derive(Template)]
+#[template(path = "forgot_password_sent.html")]
+struct ForgotPasswordSentTemplate {}
+
+#[derive(Template)]
+#[template(path = "emails/password_reset.txt")]
+struct PasswordResetEmailTemplate {
+    name: String,
+    link: String,
+    ttl_minutes: i64,
+}
+
+/// `token` is `None` when the link is unknown, expired or already used.
+#[derive(Template)]
+#[template(path = "reset_password.html")]
+struct ResetPasswordTemplate {
+    token: Option<String>,
+    errors: ValidationErrors,
+}
+
+/// The reset form alone, swapped back in with the field errors.
+#[derive(Template)]
+#[template(path = "reset_password_form.html")]
+struct ResetPasswordFormTemplate {
+    token: String,
+    errors: ValidationErrors,
+}
+
+/// Emails the user a password reset link, replacing any earlier one.
+pub async fn send_password_reset_email(state: &AppState, user: &UserDb) -> Result<(), AppError> {
+    let mut connection = state.pool.get()?;
+
+    let user_id = user.id;
+    let token = tokio::task::spawn_blocking(move || {
+        password_reset_repository::create_token(&mut connection, user_id)
+    })
+    .await??;
+
+    let body = PasswordResetEmailTemplate {
+        name: user.name.clone(),
+        link: state.config.public_url(&format!("/reset-password/{}", token)),
+        ttl_minutes: TOKEN_TTL_MINUTES,
+    }
+    .render()?;
+
+    state
+        .mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Reset your password".to_string(),
+            body,
+        })
+        .await?;
+
+    Ok(())
+}
+
+mod get {
+    use super::*;
+
+    pub async fn forgot_password() -> impl IntoResponse {
+        HtmlResponse(ForgotPasswordTemplate {})
+    }
+
+    pub async fn reset_password(
+        State(state): State<AppState>,
+        Path(token): Path<String>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let mut connection = state.pool.get()?;
+
+        let lookup = token.clone();
+        let reset_token = tokio::task::spawn_blocking(move || {
+            password_reset_repository::get_valid(&mut connection, &lookup)
+        })
+        .await??;
+
+        let template = ResetPasswordTemplate {
+            token: reset_token.map(|_| token),
+            errors: ValidationErrors::default(),
+        };
+
+        Ok(HtmlResponse(template))
+    }
+}
+
+mod post {
+    use super::*;
+
+    /// Every request counts against the email and the address, whether or
+    /// not the email has an account, so nobody gets flooded with emails. The
+    /// email goes out in the background so both cases take as long.
+    pub async fn forgot_password(
+        client: RequestClient,
+        State(state): State<AppState>,
+        Form(form): Form<ForgotPasswordForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let mut connection = state.pool.get()?;
+        let email_lockout = state.config.auth.account_lockout();
+        let ip_lockout = state.config.auth.ip_lockout();
+
+        let mut keys = vec![(
+            login_throttle_repository::password_reset_key(&form.email),
+            email_lockout,
+        )];
+        if let Some(ip_address) = &client.ip_address {
+            keys.push((login_throttle_repository::password_reset_ip_key(ip_address), ip_lockout));
+        }
+        let locked_until = tokio::task::spawn_blocking(move || {
+            login_throttle_repository::count_request(&mut connection, keys)
+        })
+        .await??;
+        if let Some(locked_until) = locked_until {
+            return Err(AppError::locked_until(locked_until));
+        }
+
+        let tasks = state.tasks.clone();
+        tasks.spawn(async move {
+            if let Err(err) = send_password_reset_email_to(&state, form.email).await {
+                error!("failed to send a password reset email: {}", err);
+            }
+        });
+
+        Ok(HtmlResponse(ForgotPasswordSentTemplate {}))
+    }
+
+    /// Emails a reset link if `email` has an account, and nothing otherwise.
+    async fn send_password_reset_email_to(state: &AppState, email: String) -> Result<(), AppError> {
+        let mut connection = state.pool.get()?;
+
+        let user = tokio::task::spawn_blocking(move || {
+            match user_repository::get_by_email(&mut connection, email) {
+                Ok(user) => Ok(Some(user)),
+                Err(diesel::result::Error::NotFound) => Ok(None),
+                Err(err) => Err(err),
+            }
+        })
+        .await??;
+
+        match user {
+            Some(user) => send_password_reset_email(state, &user).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn reset_password(
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        Path(token): Path<String>,
+        Form(form): Form<ResetPasswordForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let mut errors = ValidationErrors::default();
+        user_repository::check_password(&form.password, &mut errors);
+        if !errors.is_empty() {
+            let template = ResetPasswordFormTemplate { token, errors };
+
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, HtmlResponse(template)).into_response());
+        }
+
+        let mut connection = state.pool.get()?;
+        let user_id = tokio::task::spawn_blocking(move || {
+            let user_id =
+                password_reset_repository::reset_password(&mut connection, &token, form.password)?;
+            if let Some(user_id) = user_id {
+                let event = NewAuditEventDb::new(AuditAction::PasswordReset, &client).user(user_id);
+                audit_repository::record(&mut connection, event)?;
+            }
+            Ok::<_, diesel::result::Error>(user_id)
+        })
+        .await??;
+
+        let Some(user_id) = user_id else {
+            return Err(AppError::BadRequest(
+                "This password reset link is invalid or has expired.".to_string(),
+            ));
+        };
+
+        // The new password hash already invalidates them, this drops the rows
+        state.session_store.delete_by_user_id(user_id, None).await?;
+
+        Ok(redirect(&headers, "/login"))
+    }
+}
@@ -1,29 +1,108 @@
-
+use axum::extract::State;
+use axum::http::StatusCode;
 use axum::response::IntoResponse;
+use axum::routing::get;
 use axum::Json;
 use axum::Router;
-use axum::routing::get;
-use hyper::StatusCode;
+use diesel::RunQueryDsl;
+use diesel_migrations::MigrationHarness;
 use serde_json::json;
 
-pub fn router() -> Router {
+use crate::config::Environment;
+use crate::db::MIGRATIONS;
+use crate::AppState;
+
+/// How long the readiness check waits for a pooled connection.
+const READY_CONNECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+pub fn router() -> Router<AppState> {
     Router::new()
         .route("/api/v1/service/status", get(self::get::status))
+        .route("/api/v1/service/health", get(self::get::health))
+        .route("/api/v1/service/ready", get(self::get::ready))
 }
 
 mod get {
     use super::*;
 
-    pub async fn status() -> impl IntoResponse {
+    pub async fn status(State(state): State<AppState>) -> impl IntoResponse {
         let version = env!("CARGO_PKG_VERSION");
-    
+        let environment = match state.config.environment {
+            Environment::Development => "development",
+            Environment::Production => "production",
+        };
+
         let response = json!({
             "data": {
                 "version": version,
+                "environment": environment,
             },
             "message": "Service is running..."
         });
         (StatusCode::OK, Json(response))
     }
-}
 
+    /// Liveness: the process is up and serving requests.
+    pub async fn health() -> impl IntoResponse {
+        let response = json!({
+            "message": "Service is alive"
+        });
+        (StatusCode::OK, Json(response))
+    }
+
+    /// Readiness: the database answers and every migration has been applied.
+    pub async fn ready(State(state): State<AppState>) -> impl IntoResponse {
+        let pool = state.pool.clone();
+        let check = tokio::task::spawn_blocking(move || -> Result<Vec<String>, String> {
+            let mut connection = pool
+                .get_timeout(READY_CONNECTION_TIMEOUT)
+                .map_err(|err| format!("no database connection: {}", err))?;
+
+            diesel::sql_query("SELECT 1")
+                .execute(&mut connection)
+                .map_err(|err| format!("database query failed: {}", err))?;
+
+            let pending = connection
+                .pending_migrations(MIGRATIONS)
+                .map_err(|err| format!("failed to check migrations: {}", err))?;
+
+            Ok(pending
+                .iter()
+                .map(|migration| migration.name().to_string())
+                .collect())
+        })
+        .await
+        .unwrap_or_else(|err| Err(format!("readiness check failed: {}", err)));
+
+        let pool_state = state.pool.state();
+        let pool = json!({
+            "max_size": state.pool.max_size(),
+            "connections": pool_state.connections,
+            "idle_connections": pool_state.idle_connections,
+            "active_connections": pool_state.connections - pool_state.idle_connections,
+        });
+
+        let database = check.is_ok();
+        let (status, message, pending) = match check {
+            Ok(pending) if pending.is_empty() => {
+                (StatusCode::OK, "Service is ready".to_string(), pending)
+            }
+            Ok(pending) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Database has pending migrations".to_string(),
+                pending,
+            ),
+            Err(message) => (StatusCode::SERVICE_UNAVAILABLE, message, Vec::new()),
+        };
+
+        let response = json!({
+            "data": {
+                "database": database,
+                "pending_migrations": pending,
+                "pool": pool,
+            },
+            "message": message
+        });
+        (status, Json(response))
+    }
+}
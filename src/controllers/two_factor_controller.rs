@@ -0,0 +1,272 @@
+use crate::client_info::RequestClient;
+use crate::controllers::html_response::{redirect, HtmlResponse};
+use crate::errors::AppError;
+use crate::impersonation::NotImpersonating;
+use crate::models::audit_event::AuditAction;
+use crate::repositories::audit_repository::{self, NewAuditEventDb};
+use crate::repositories::auth_backend::{AuthSession, Backend};
+use crate::repositories::two_factor_repository::{self, TwoFactorCodeForm};
+use crate::totp;
+use askama::Template;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Form, Router,
+};
+use axum_login::login_required;
+
+use crate::AppState;
+
+/// Shown by authenticator apps next to the codes.
+const TOTP_ISSUER: &str = "Informator";
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/account/two-factor", get(self::get::two_factor))
+        .route("/account/two-factor/setup", post(self::post::setup))
+        .route("/account/two-factor/enable", post(self::post::enable))
+        .route(
+            "/account/two-factor/recovery-codes",
+            post(self::post::recovery_codes),
+        )
+        .route("/account/two-factor/disable", post(self::post::disable))
+        .route_layer(login_required!(Backend, login_url = "/login"))
+}
+
+/// `remaining_recovery_codes` is `None` while two-factor authentication is off.
+#[derive(Template)]
+#[template(path = "account_two_factor.html")]
+struct TwoFactorTemplate {
+    remaining_recovery_codes: Option<i64>,
+    error: Option<String>,
+}
+
+/// The QR code to scan and the form confirming the first code.
+#[derive(Template)]
+#[template(path = "two_factor_setup.html")]
+struct TwoFactorSetupTemplate {
+    qr_code_svg: String,
+    secret: String,
+    error: Option<String>,
+}
+
+/// Shown once, only hashes of the codes are kept.
+#[derive(Template)]
+#[template(path = "two_factor_recovery_codes.html")]
+struct RecoveryCodesTemplate {
+    codes: Vec<String>,
+}
+
+/// The form to replace the recovery codes or turn two-factor authentication
+/// off, swapped back in when the code is wrong.
+#[derive(Template)]
+#[template(path = "two_factor_manage.html")]
+struct TwoFactorManageTemplate {
+    remaining_recovery_codes: i64,
+    error: Option<String>,
+}
+
+impl TwoFactorSetupTemplate {
+    fn new(email: &str, secret: &[u8], error: Option<String>) -> Self {
+        let uri = totp::provisioning_uri(TOTP_ISSUER, email, secret);
+
+        Self {
+            qr_code_svg: totp::qr_code_svg(&uri),
+            secret: totp::encode_secret(secret),
+            error,
+        }
+    }
+}
+
+mod get {
+    use super::*;
+
+    pub async fn two_factor(
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+
+        let mut connection = state.pool.get()?;
+        let remaining_recovery_codes = match user.totp_enabled_at {
+            Some(_) => Some(
+                tokio::task::spawn_blocking(move || {
+                    two_factor_repository::count_unused_recovery_codes(&mut connection, user.id)
+                })
+                .await??,
+            ),
+            None => None,
+        };
+
+        Ok(HtmlResponse(TwoFactorTemplate {
+            remaining_recovery_codes,
+            error: None,
+        }))
+    }
+}
+
+mod post {
+    use super::*;
+
+    /// Starts over with a new secret, replacing any earlier unconfirmed one.
+    pub async fn setup(
+        _: NotImpersonating,
+        headers: HeaderMap,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+
+        let secret = totp::generate_secret();
+        let encrypted_secret = state.totp_cipher.encrypt(user.id, &secret);
+
+        let mut connection = state.pool.get()?;
+        let started = tokio::task::spawn_blocking(move || {
+            two_factor_repository::start_enrolment(&mut connection, user.id, encrypted_secret)
+        })
+        .await??;
+        if !started {
+            return Ok(redirect(&headers, "/account/two-factor"));
+        }
+
+        Ok(HtmlResponse(TwoFactorSetupTemplate::new(&user.email, &secret, None)).into_response())
+    }
+
+    /// Turns two-factor authentication on once the user proves their app
+    /// produces the right codes.
+    pub async fn enable(
+        _: NotImpersonating,
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Form(form): Form<TwoFactorCodeForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+
+        let mut connection = state.pool.get()?;
+        let cipher = state.totp_cipher.clone();
+        let user_id = user.id;
+        let result = tokio::task::spawn_blocking(move || {
+            let totp = two_factor_repository::get_totp(&mut connection, user_id)?;
+            let secret = match totp.totp_secret {
+                Some(sealed) if totp.totp_enabled_at.is_none() => cipher.decrypt(user_id, &sealed),
+                _ => None,
+            };
+            let Some(secret) = secret else {
+                return Ok(None);
+            };
+
+            let now = chrono::Utc::now().timestamp();
+            let codes = match totp::verify(&secret, &form.code, now, None) {
+                Some(step) => two_factor_repository::enable(&mut connection, user_id, step)?,
+                None => None,
+            };
+            if codes.is_some() {
+                let event = NewAuditEventDb::new(AuditAction::TwoFactorEnabled, &client).user(user_id);
+                audit_repository::record(&mut connection, event)?;
+            }
+
+            Ok::<_, AppError>(Some((secret, codes)))
+        })
+        .await??;
+
+        match result {
+            // Enabled already, or the setup was never started
+            None => Ok(redirect(&headers, "/account/two-factor")),
+            Some((_, Some(codes))) => Ok(HtmlResponse(RecoveryCodesTemplate { codes }).into_response()),
+            Some((secret, None)) => {
+                let error = Some("That code didn't work, check the time on your device.".to_string());
+                let template = TwoFactorSetupTemplate::new(&user.email, &secret, error);
+
+                Ok((StatusCode::UNPROCESSABLE_ENTITY, HtmlResponse(template)).into_response())
+            }
+        }
+    }
+
+    /// Replaces the recovery codes, for when they run low or got lost.
+    pub async fn recovery_codes(
+        _: NotImpersonating,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Form(form): Form<TwoFactorCodeForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.clone().ok_or(AppError::Unauthorized)?;
+
+        if let Some(rejected) = check_code(&state, &auth_session, &client, form.code).await? {
+            return Ok(rejected);
+        }
+
+        let mut connection = state.pool.get()?;
+        let codes = tokio::task::spawn_blocking(move || {
+            two_factor_repository::replace_recovery_codes(&mut connection, user.id)
+        })
+        .await??;
+
+        Ok(HtmlResponse(RecoveryCodesTemplate { codes }).into_response())
+    }
+
+    pub async fn disable(
+        _: NotImpersonating,
+        headers: HeaderMap,
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+        Form(form): Form<TwoFactorCodeForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.clone().ok_or(AppError::Unauthorized)?;
+
+        if let Some(rejected) = check_code(&state, &auth_session, &client, form.code).await? {
+            return Ok(rejected);
+        }
+
+        let mut connection = state.pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            two_factor_repository::disable(&mut connection, user.id)?;
+            let event = NewAuditEventDb::new(AuditAction::TwoFactorDisabled, &client).user(user.id);
+            audit_repository::record(&mut connection, event)
+        })
+        .await??;
+
+        Ok(redirect(&headers, "/account/two-factor"))
+    }
+
+    /// Changes need a current code too, a session alone isn't enough. Returns
+    /// the form with the error when the code is wrong.
+    async fn check_code(
+        state: &AppState,
+        auth_session: &AuthSession,
+        client: &RequestClient,
+        code: String,
+    ) -> Result<Option<axum::response::Response>, AppError> {
+        let user = auth_session.user.as_ref().ok_or(AppError::Unauthorized)?;
+
+        let verified = auth_session
+            .backend
+            .verify_second_factor(user, code, client.ip_address.clone())
+            .await;
+        let (status, error) = match verified {
+            Ok(true) => return Ok(None),
+            Ok(false) => (StatusCode::UNAUTHORIZED, "That code didn't work.".to_string()),
+            Err(err @ AppError::TooManyAttempts { .. }) => (err.status_code(), err.user_message()),
+            Err(err) => return Err(err),
+        };
+
+        let mut connection = state.pool.get()?;
+        let user_id = user.id;
+        let remaining_recovery_codes = tokio::task::spawn_blocking(move || {
+            two_factor_repository::count_unused_recovery_codes(&mut connection, user_id)
+        })
+        .await??;
+
+        let template = TwoFactorManageTemplate {
+            remaining_recovery_codes,
+            error: Some(error),
+        };
+
+        Ok(Some((status, HtmlResponse(template)).into_response()))
+    }
+}
@@ -0,0 +1,153 @@
+use crate::client_info::RequestClient;
+use crate::controllers::html_response::HtmlResponse;
+use crate::errors::AppError;
+use crate::mailer::Email;
+use crate::repositories::account_unlock_repository::{
+    self, UnlockAccountForm, TOKEN_TTL_MINUTES,
+};
+use crate::repositories::{login_throttle_repository, user_repository};
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::get,
+    Form, Router,
+};
+use log::error;
+
+use crate::AppState;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/unlock-account",
+            get(self::get::unlock_account).post(self::post::unlock_account),
+        )
+        .route("/unlock-account/:token", get(self::get::unlock_token))
+}
+
+#[derive(Template)]
+#[template(path = "unlock_account.html")]
+struct UnlockAccountTemplate {}
+
+/// Replaces the form once submitted, the same whether or not the email has an
+/// account.
+#[derive(Template)]
+#[template(path = "unlock_account_sent.html")]
+struct UnlockAccountSentTemplate {}
+
+#[derive(Template)]
+#[template(path = "account_unlocked.html")]
+struct AccountUnlockedTemplate {
+    unlocked: bool,
+}
+
+#[derive(Template)]
+#[template(path = "emails/unlock_account.txt")]
+struct UnlockAccountEmailTemplate {
+    name: String,
+    link: String,
+    ttl_minutes: i64,
+}
+
+mod get {
+    use super::*;
+
+    pub async fn unlock_account() -> impl IntoResponse {
+        HtmlResponse(UnlockAccountTemplate {})
+    }
+
+    pub async fn unlock_token(
+        State(state): State<AppState>,
+        Path(token): Path<String>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let mut connection = state.pool.get()?;
+
+        let user_id = tokio::task::spawn_blocking(move || {
+            account_unlock_repository::unlock(&mut connection, &token)
+        })
+        .await??;
+
+        Ok(HtmlResponse(AccountUnlockedTemplate {
+            unlocked: user_id.is_some(),
+        }))
+    }
+}
+
+mod post {
+    use super::*;
+
+    /// Throttled and sent in the background like password reset requests, so
+    /// nobody gets flooded with emails or learns which emails have accounts.
+    pub async fn unlock_account(
+        client: RequestClient,
+        State(state): State<AppState>,
+        Form(form): Form<UnlockAccountForm>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let mut connection = state.pool.get()?;
+
+        let mut keys = vec![(
+            login_throttle_repository::unlock_key(&form.email),
+            state.config.auth.account_lockout(),
+        )];
+        if let Some(ip_address) = &client.ip_address {
+            keys.push((
+                login_throttle_repository::unlock_ip_key(ip_address),
+                state.config.auth.ip_lockout(),
+            ));
+        }
+        let locked_until = tokio::task::spawn_blocking(move || {
+            login_throttle_repository::count_request(&mut connection, keys)
+        })
+        .await??;
+        if let Some(locked_until) = locked_until {
+            return Err(AppError::locked_until(locked_until));
+        }
+
+        let tasks = state.tasks.clone();
+        tasks.spawn(async move {
+            if let Err(err) = send_unlock_email(&state, form.email).await {
+                error!("failed to send an unlock email: {}", err);
+            }
+        });
+
+        Ok(HtmlResponse(UnlockAccountSentTemplate {}))
+    }
+
+    /// Emails an unlock link if `email` has an account, and nothing otherwise.
+    async fn send_unlock_email(state: &AppState, email: String) -> Result<(), AppError> {
+        let mut connection = state.pool.get()?;
+
+        let result = tokio::task::spawn_blocking(move || {
+            let user = match user_repository::get_by_email(&mut connection, email) {
+                Ok(user) => user,
+                Err(diesel::result::Error::NotFound) => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            let token = account_unlock_repository::create_token(&mut connection, user.id)?;
+
+            Ok(Some((user, token)))
+        })
+        .await??;
+
+        if let Some((user, token)) = result {
+            let body = UnlockAccountEmailTemplate {
+                name: user.name,
+                link: state.config.public_url(&format!("/unlock-account/{}", token)),
+                ttl_minutes: TOKEN_TTL_MINUTES,
+            }
+            .render()?;
+
+            state
+                .mailer
+                .send(Email {
+                    to: user.email,
+                    subject: "Unlock your account".to_string(),
+                    body,
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+}
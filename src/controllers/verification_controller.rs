@@ -0,0 +1,241 @@
+use crate::client_info::RequestClient;
+use crate::controllers::html_response::{redirect, HtmlResponse};
+use crate::errors::AppError;
+use crate::mailer::Email;
+use crate::models::audit_event::AuditAction;
+use crate::repositories::audit_repository::{self, NewAuditEventDb};
+use crate::repositories::auth_backend::{AuthSession, Backend};
+use crate::repositories::email_verification_repository::{self, TOKEN_TTL_HOURS};
+use crate::repositories::login_throttle_repository;
+use crate::repositories::user_repository::UserDb;
+use askama::Template;
+use axum::{
+    extract::{Path, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use axum_login::login_required;
+use serde_json::json;
+
+use crate::AppState;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/verify-email", get(self::get::verify_email))
+        .route("/verify-email/resend", post(self::post::resend))
+        .route_layer(login_required!(Backend, login_url = "/login"))
+        // Following the link works without being logged in, e.g. on a phone
+        .route("/verify-email/:token", get(self::get::verify_token))
+}
+
+#[derive(Template)]
+#[template(path = "verify_email.html")]
+struct VerifyEmailTemplate {
+    email: String,
+}
+
+#[derive(Template)]
+#[template(path = "verify_email_sent.html")]
+struct VerifyEmailSentTemplate {
+    email: String,
+}
+
+#[derive(Template)]
+#[template(path = "email_verified.html")]
+struct EmailVerifiedTemplate {
+    verified: bool,
+    /// The new email, when the link was for an email change.
+    changed_to: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "emails/verify_email.txt")]
+struct VerifyEmailEmailTemplate {
+    name: String,
+    link: String,
+    ttl_hours: i64,
+}
+
+/// Emails the user a fresh verification link, replacing any earlier one.
+pub async fn send_verification_email(state: &AppState, user: &UserDb) -> Result<(), AppError> {
+    let mut connection = state.pool.get()?;
+
+    let user_id = user.id;
+    let token = tokio::task::spawn_blocking(move || {
+        email_verification_repository::create_token(&mut connection, user_id)
+    })
+    .await??;
+
+    let body = VerifyEmailEmailTemplate {
+        name: user.name.clone(),
+        link: state.config.public_url(&format!("/verify-email/{}", token)),
+        ttl_hours: TOKEN_TTL_HOURS,
+    }
+    .render()?;
+
+    state
+        .mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Verify your email".to_string(),
+            body,
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Template)]
+#[template(path = "emails/confirm_email_change.txt")]
+struct ConfirmEmailChangeEmailTemplate {
+    name: String,
+    link: String,
+    ttl_hours: i64,
+}
+
+/// Emails a link to `new_email` that switches the user to it, the account
+/// keeps its current email until then.
+pub async fn send_email_change_email(
+    state: &AppState,
+    user: &UserDb,
+    new_email: String,
+) -> Result<(), AppError> {
+    let mut connection = state.pool.get()?;
+
+    let (user_id, to) = (user.id, new_email.clone());
+    let token = tokio::task::spawn_blocking(move || {
+        email_verification_repository::create_email_change_token(&mut connection, user_id, new_email)
+    })
+    .await??;
+
+    let body = ConfirmEmailChangeEmailTemplate {
+        name: user.name.clone(),
+        link: state.config.public_url(&format!("/verify-email/{}", token)),
+        ttl_hours: TOKEN_TTL_HOURS,
+    }
+    .render()?;
+
+    state
+        .mailer
+        .send(Email {
+            to,
+            subject: "Confirm your new email".to_string(),
+            body,
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Route layer sending logged in users with an unverified email to the
+/// verification page, when the backend requires verified emails. It goes
+/// inside `login_required!`, which handles anonymous users.
+pub async fn verified_email_required(
+    auth_session: AuthSession,
+    request: Request,
+    next: Next,
+) -> Response {
+    let unverified = auth_session
+        .user
+        .as_ref()
+        .is_some_and(|user| user.email_verified_at.is_none());
+
+    if unverified && auth_session.backend.requires_verified_email() {
+        return redirect(request.headers(), "/verify-email");
+    }
+
+    next.run(request).await
+}
+
+mod get {
+    use super::*;
+
+    pub async fn verify_email(
+        headers: HeaderMap,
+        auth_session: AuthSession,
+    ) -> Result<Response, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+        if user.email_verified_at.is_some() {
+            return Ok(redirect(&headers, "/"));
+        }
+
+        Ok(HtmlResponse(VerifyEmailTemplate { email: user.email }).into_response())
+    }
+
+    pub async fn verify_token(
+        client: RequestClient,
+        State(state): State<AppState>,
+        Path(token): Path<String>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let mut connection = state.pool.get()?;
+
+        let verified = tokio::task::spawn_blocking(move || {
+            let verified = email_verification_repository::verify(&mut connection, &token)?;
+            if let Some(verified) = &verified {
+                if let Some(previous_email) = &verified.previous_email {
+                    let event = NewAuditEventDb::new(AuditAction::EmailChanged, &client)
+                        .user(verified.user_id)
+                        .metadata(json!({
+                            "from_hash": audit_repository::email_hash(previous_email),
+                            "to_hash": audit_repository::email_hash(&verified.email),
+                        }));
+                    audit_repository::record(&mut connection, event)?;
+                }
+            }
+
+            Ok::<_, diesel::result::Error>(verified)
+        })
+        .await??;
+
+        Ok(HtmlResponse(EmailVerifiedTemplate {
+            changed_to: verified
+                .as_ref()
+                .filter(|verified| verified.previous_email.is_some())
+                .map(|verified| verified.email.clone()),
+            verified: verified.is_some(),
+        }))
+    }
+}
+
+mod post {
+    use super::*;
+
+    /// Counts against the email and the address like password reset
+    /// requests, every resend is another email.
+    pub async fn resend(
+        client: RequestClient,
+        State(state): State<AppState>,
+        auth_session: AuthSession,
+    ) -> Result<impl IntoResponse, AppError> {
+        let user = auth_session.user.ok_or(AppError::Unauthorized)?;
+        if user.email_verified_at.is_some() {
+            return Err(AppError::BadRequest("Your email is already verified.".to_string()));
+        }
+
+        let mut connection = state.pool.get()?;
+        let mut keys = vec![(
+            login_throttle_repository::verification_key(&user.email),
+            state.config.auth.account_lockout(),
+        )];
+        if let Some(ip_address) = &client.ip_address {
+            keys.push((
+                login_throttle_repository::verification_ip_key(ip_address),
+                state.config.auth.ip_lockout(),
+            ));
+        }
+        let locked_until = tokio::task::spawn_blocking(move || {
+            login_throttle_repository::count_request(&mut connection, keys)
+        })
+        .await??;
+        if let Some(locked_until) = locked_until {
+            return Err(AppError::locked_until(locked_until));
+        }
+
+        send_verification_email(&state, &user).await?;
+
+        Ok(HtmlResponse(VerifyEmailSentTemplate { email: user.email }))
+    }
+}
@@ -1 +1,65 @@
+use diesel::pg::{Pg, PgConnection};
+use diesel::migration::{Migration, MigrationSource};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
 pub mod schema;
+
+/// The migrations from `migrations/`, compiled into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+pub type MigrationError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Applies every pending migration and returns the names of those applied.
+pub fn run_pending_migrations(connection: &mut PgConnection) -> Result<Vec<String>, MigrationError> {
+    let applied = connection.run_pending_migrations(MIGRATIONS)?;
+
+    Ok(applied.iter().map(|version| version.to_string()).collect())
+}
+
+/// Reverts the most recently applied migration and returns its version.
+pub fn revert_last_migration(connection: &mut PgConnection) -> Result<String, MigrationError> {
+    let reverted = connection.revert_last_migration(MIGRATIONS)?;
+
+    Ok(reverted.to_string())
+}
+
+/// Lists every embedded migration by name, along with whether it's applied.
+pub fn migration_status(connection: &mut PgConnection) -> Result<Vec<(String, bool)>, MigrationError> {
+    let applied = connection.applied_migrations()?;
+    let migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS)?;
+
+    Ok(migrations
+        .iter()
+        .map(|migration| {
+            let name = migration.name();
+            (name.to_string(), applied.contains(&name.version()))
+        })
+        .collect())
+}
+
+/// For the repository tests, which run against the database in `DATABASE_URL`
+/// with the migrations applied.
+#[cfg(test)]
+pub fn test_connection() -> PgConnection {
+    use diesel::Connection;
+
+    PgConnection::establish(&test_database_url()).unwrap()
+}
+
+/// A small pool on the same database, for tests of code that takes one.
+#[cfg(test)]
+pub fn test_pool() -> crate::PgPool {
+    use diesel::r2d2::ConnectionManager;
+
+    crate::PgPool::builder()
+        .max_size(2)
+        .build(ConnectionManager::<PgConnection>::new(test_database_url()))
+        .expect("Failed to create pool.")
+}
+
+#[cfg(test)]
+fn test_database_url() -> String {
+    dotenv::dotenv().ok();
+
+    std::env::var("DATABASE_URL").expect("DATABASE_URL must be set to run the repository tests")
+}
@@ -1,10 +1,155 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    account_unlock_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    audit_events (id) {
+        id -> Uuid,
+        actor_id -> Nullable<Uuid>,
+        target_id -> Nullable<Uuid>,
+        #[max_length = 64]
+        action -> Varchar,
+        ip_address -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        metadata -> Jsonb,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    email_verification_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+        #[max_length = 255]
+        new_email -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    feed_events (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        #[max_length = 50]
+        kind -> Varchar,
+        #[max_length = 255]
+        subject -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    impersonations (id) {
+        id -> Uuid,
+        admin_id -> Uuid,
+        user_id -> Uuid,
+        started_at -> Timestamp,
+        ended_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    login_throttles (key) {
+        #[max_length = 320]
+        key -> Varchar,
+        failures -> Int4,
+        last_failed_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    password_reset_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    permissions (id) {
+        id -> Uuid,
+        #[max_length = 100]
+        name -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    role_permissions (role_id, permission_id) {
+        role_id -> Uuid,
+        permission_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    roles (id) {
+        id -> Uuid,
+        #[max_length = 50]
+        name -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     sessions (id) {
         id -> Text,
         data -> Bytea,
         expiry_date -> Timestamptz,
+        user_id -> Nullable<Uuid>,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        ip_address -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    todos (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        #[max_length = 255]
+        description -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        done -> Bool,
+        position -> Int4,
+        completed_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    user_roles (user_id, role_id) {
+        user_id -> Uuid,
+        role_id -> Uuid,
+        created_at -> Timestamp,
     }
 }
 
@@ -19,10 +164,41 @@ diesel::table! {
         password -> Varchar,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        email_verified_at -> Nullable<Timestamp>,
+        totp_secret -> Nullable<Bytea>,
+        totp_enabled_at -> Nullable<Timestamp>,
+        totp_last_step -> Nullable<Int8>,
+        disabled_at -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
+diesel::joinable!(account_unlock_tokens -> users (user_id));
+diesel::joinable!(email_verification_tokens -> users (user_id));
+diesel::joinable!(feed_events -> users (user_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(role_permissions -> permissions (permission_id));
+diesel::joinable!(role_permissions -> roles (role_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(todos -> users (user_id));
+diesel::joinable!(user_roles -> roles (role_id));
+diesel::joinable!(user_roles -> users (user_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
+    account_unlock_tokens,
+    audit_events,
+    email_verification_tokens,
+    feed_events,
+    impersonations,
+    login_throttles,
+    password_reset_tokens,
+    permissions,
+    recovery_codes,
+    role_permissions,
+    roles,
     sessions,
+    todos,
+    user_roles,
     users,
 );
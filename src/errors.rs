@@ -0,0 +1,194 @@
+use askama::Template;
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse, Response};
+use diesel::result::DatabaseErrorKind;
+use log::{error, warn};
+
+use crate::repositories::auth_backend::Backend;
+
+/// The error type returned by handlers and the auth backend.
+///
+/// Converting it into a response only sets the status code and the message
+/// that is safe to show to the user; `render_errors` turns that into HTML.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("connection pool error: {0}")]
+    Pool(#[from] diesel::r2d2::PoolError),
+    #[error("blocking task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("failed to render template: {0}")]
+    Template(#[from] askama::Error),
+    #[error("session error: {0}")]
+    Session(#[from] tower_sessions::session::Error),
+    #[error("session store error: {0}")]
+    SessionStore(#[from] tower_sessions::session_store::Error),
+    #[error("mail error: {0}")]
+    Mail(#[from] crate::mailer::MailError),
+    #[error("failed to build archive: {0}")]
+    Archive(#[from] zip::result::ZipError),
+    #[error("not found")]
+    NotFound,
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("forbidden")]
+    Forbidden,
+    #[error("account disabled")]
+    AccountDisabled,
+    #[error("too many attempts, retry after {retry_after_secs}s")]
+    TooManyAttempts { retry_after_secs: i64 },
+}
+
+impl AppError {
+    /// Refuses a throttled request until `locked_until`.
+    pub fn locked_until(locked_until: chrono::NaiveDateTime) -> Self {
+        let retry_after = locked_until - chrono::Utc::now().naive_utc();
+
+        AppError::TooManyAttempts {
+            retry_after_secs: retry_after.num_seconds() + 1,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Database(diesel::result::Error::NotFound) => StatusCode::NOT_FOUND,
+            AppError::Database(diesel::result::Error::DatabaseError(
+                DatabaseErrorKind::UniqueViolation,
+                _,
+            )) => StatusCode::CONFLICT,
+            AppError::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden | AppError::AccountDisabled => StatusCode::FORBIDDEN,
+            AppError::TooManyAttempts { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Database(_)
+            | AppError::Join(_)
+            | AppError::Template(_)
+            | AppError::Session(_)
+            | AppError::SessionStore(_)
+            | AppError::Mail(_)
+            | AppError::Archive(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The message shown to the user. Internal details only go to the log.
+    pub fn user_message(&self) -> String {
+        match self {
+            AppError::BadRequest(message) => message.clone(),
+            AppError::AccountDisabled => "This account has been disabled.".to_string(),
+            AppError::TooManyAttempts { retry_after_secs } => format!(
+                "Too many failed attempts, please try again in {}.",
+                describe_duration(*retry_after_secs)
+            ),
+            _ => match self.status_code() {
+                StatusCode::NOT_FOUND => "We couldn't find what you were looking for.".to_string(),
+                StatusCode::CONFLICT => "That already exists.".to_string(),
+                StatusCode::UNAUTHORIZED => "You need to log in first.".to_string(),
+                StatusCode::FORBIDDEN => "You don't have access to that.".to_string(),
+                StatusCode::SERVICE_UNAVAILABLE => {
+                    "The service is busy right now, please try again.".to_string()
+                }
+                _ => "Something went wrong on our side.".to_string(),
+            },
+        }
+    }
+}
+
+/// Rounds up to whole minutes past the first one, e.g. "45 seconds", "2 minutes".
+fn describe_duration(secs: i64) -> String {
+    match secs {
+        ..=1 => "a second".to_string(),
+        2..=59 => format!("{} seconds", secs),
+        60 => "a minute".to_string(),
+        _ => format!("{} minutes", (secs + 59) / 60),
+    }
+}
+
+impl From<axum_login::Error<Backend>> for AppError {
+    fn from(err: axum_login::Error<Backend>) -> Self {
+        match err {
+            axum_login::Error::Session(err) => AppError::Session(err),
+            axum_login::Error::Backend(err) => err,
+        }
+    }
+}
+
+/// Marks a response as an application error, carrying the user facing message
+/// for `render_errors`.
+#[derive(Clone, Debug)]
+struct ErrorMessage(String);
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        if status.is_server_error() {
+            error!("{}", self);
+        } else {
+            warn!("{}", self);
+        }
+
+        let message = self.user_message();
+        let mut response = (status, message.clone()).into_response();
+        response.extensions_mut().insert(ErrorMessage(message));
+
+        response
+    }
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate {
+    pub status: u16,
+    pub message: String,
+}
+
+#[derive(Template)]
+#[template(path = "error_fragment.html")]
+struct ErrorFragmentTemplate {
+    pub message: String,
+}
+
+/// Middleware rendering `AppError` responses and bare 403s: htmx requests get
+/// a fragment swapped into `#errors`, everything else gets a full error page.
+pub async fn render_errors(request: Request, next: Next) -> Response {
+    let is_htmx = request.headers().contains_key("HX-Request");
+
+    let response = next.run(request).await;
+    let status = response.status();
+    let message = match response.extensions().get::<ErrorMessage>().cloned() {
+        Some(ErrorMessage(message)) => message,
+        // `permission_required!` answers with a bare status of its own
+        None if status == StatusCode::FORBIDDEN => AppError::Forbidden.user_message(),
+        None => return response,
+    };
+
+    let rendered = match is_htmx {
+        true => ErrorFragmentTemplate { message }.render(),
+        false => ErrorTemplate {
+            status: status.as_u16(),
+            message,
+        }
+        .render(),
+    };
+
+    match (rendered, is_htmx) {
+        (Ok(html), true) => (
+            status,
+            [("HX-Retarget", "#errors"), ("HX-Reswap", "innerHTML")],
+            Html(html),
+        )
+            .into_response(),
+        (Ok(html), false) => (status, Html(html)).into_response(),
+        // Keep the plain text body we already have
+        (Err(err), _) => {
+            error!("failed to render error page: {}", err);
+            response
+        }
+    }
+}
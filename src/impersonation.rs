@@ -0,0 +1,91 @@
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+
+/// Kept in the session while an admin is logged in as someone else, so they
+/// can switch back.
+pub const IMPERSONATOR_KEY: &str = "informator.impersonator";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Impersonator {
+    pub admin_id: Uuid,
+    pub impersonation_id: Uuid,
+}
+
+impl Impersonator {
+    /// The admin logged in as the user of the request, if any.
+    pub async fn from_parts(parts: &Parts) -> Result<Option<Self>, AppError> {
+        match parts.extensions.get::<Session>() {
+            Some(session) => Ok(session.get(IMPERSONATOR_KEY).await?),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Refuses the request while an admin is logged in as the user. Admins get to
+/// see what the user sees, not to change their password, email, two factor
+/// authentication or take their account and data.
+pub struct NotImpersonating;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for NotImpersonating
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match Impersonator::from_parts(parts).await? {
+            Some(_) => Err(AppError::Forbidden),
+            None => Ok(Self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::Request;
+    use tower_sessions::MemoryStore;
+
+    use super::*;
+
+    async fn parts(impersonator: Option<Impersonator>) -> Parts {
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+        if let Some(impersonator) = impersonator {
+            session.insert(IMPERSONATOR_KEY, impersonator).await.unwrap();
+        }
+        let (mut parts, _) = Request::post("/account/password").body(()).unwrap().into_parts();
+        parts.extensions.insert(session);
+
+        parts
+    }
+
+    #[tokio::test]
+    async fn password_changes_are_refused_while_impersonating() {
+        let impersonator = Impersonator {
+            admin_id: Uuid::new_v4(),
+            impersonation_id: Uuid::new_v4(),
+        };
+        let mut parts = parts(Some(impersonator)).await;
+
+        let result = NotImpersonating::from_request_parts(&mut parts, &()).await;
+
+        assert!(matches!(result, Err(AppError::Forbidden)));
+        assert_eq!(Impersonator::from_parts(&parts).await.unwrap(), Some(impersonator));
+    }
+
+    #[tokio::test]
+    async fn users_change_their_own_password() {
+        let mut parts = parts(None).await;
+
+        assert!(NotImpersonating::from_request_parts(&mut parts, &()).await.is_ok());
+        assert_eq!(Impersonator::from_parts(&parts).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::info;
+use uuid::Uuid;
+
+use super::{format_message, Email, MailError, Mailer};
+
+/// Writes every email as an `.eml` file into a directory, where tests and
+/// developers can pick them up.
+#[derive(Debug)]
+pub struct FileMailer {
+    from: String,
+    directory: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(from: String, directory: impl Into<PathBuf>) -> Self {
+        Self {
+            from,
+            directory: directory.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+
+        // Sorting the directory lists the emails in the order they were sent
+        let path = self.directory.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            Uuid::new_v4()
+        ));
+        tokio::fs::write(&path, format_message(&self.from, &email)).await?;
+        info!("Wrote email to {}", path.display());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn send_writes_an_eml_file() {
+        let directory = std::env::temp_dir().join(format!("informator-mail-{}", Uuid::new_v4()));
+        let mailer = FileMailer::new("Informator <no-reply@localhost>".to_string(), &directory);
+
+        mailer
+            .send(Email {
+                to: "samuel@example.com".to_string(),
+                subject: "Hello".to_string(),
+                body: "Hi Samuel".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let mut entries = std::fs::read_dir(&directory).unwrap();
+        let message = std::fs::read_to_string(entries.next().unwrap().unwrap().path()).unwrap();
+        assert!(message.starts_with("From: Informator <no-reply@localhost>\r\n"));
+        assert!(message.contains("To: samuel@example.com\r\n"));
+        assert!(message.ends_with("\r\n\r\nHi Samuel"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
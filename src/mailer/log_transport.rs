@@ -0,0 +1,25 @@
+use async_trait::async_trait;
+use log::info;
+
+use super::{format_message, Email, MailError, Mailer};
+
+/// Writes every email to the log instead of sending it, for development.
+#[derive(Debug)]
+pub struct LogMailer {
+    from: String,
+}
+
+impl LogMailer {
+    pub fn new(from: String) -> Self {
+        Self { from }
+    }
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        info!("Sending email:\n{}", format_message(&self.from, &email));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::config::{MailConfig, MailTransport};
+
+pub mod file_transport;
+pub mod log_transport;
+
+/// A plain text email.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MailError {
+    #[error("failed to write email: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Sends outbound email. Handlers get one through `AppState::mailer`, so the
+/// transport is picked by configuration.
+#[async_trait]
+pub trait Mailer: Debug + Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+pub fn from_config(config: &MailConfig) -> Arc<dyn Mailer> {
+    match config.transport {
+        MailTransport::Log => Arc::new(log_transport::LogMailer::new(config.from.clone())),
+        MailTransport::File => Arc::new(file_transport::FileMailer::new(
+            config.from.clone(),
+            config.directory.clone(),
+        )),
+    }
+}
+
+/// Formats `email` as a minimal RFC 5322 message.
+fn format_message(from: &str, email: &Email) -> String {
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+        from, email.to, email.subject, email.body
+    )
+}
@@ -1,25 +1,48 @@
 use axum::Router;
-use axum_login::{AuthManagerLayerBuilder, login_required};
-use log::info;
+use axum_login::AuthManagerLayerBuilder;
+use log::{error, info, warn};
 use time::Duration;
+use tokio_util::task::TaskTracker;
 use tower_sessions::{Expiry, SessionManagerLayer};
 // use serde::{Deserialize, Serialize};
-use std::env;
-// use tokio::signal;
+use std::future::IntoFuture;
+use std::sync::Arc;
 use crate::{
-    controllers::{home_controller, auth_controller},
-    repositories::{postgres_store::PostgresStore, auth_backend::Backend},
+    cli::Command,
+    config::{Config, Environment},
+    mailer::Mailer,
+    shutdown::Shutdown,
+    totp::SecretCipher,
+    controllers::{
+        account_controller, admin_controller, auth_controller, home_controller, password_reset_controller,
+        service_controller, two_factor_controller, unlock_controller, verification_controller,
+    },
+    repositories::{
+        auth_backend::{Backend, AUTH_DATA_KEY},
+        postgres_store::PostgresStore,
+    },
 };
 use diesel::{
     pg::PgConnection,
     r2d2::{ConnectionManager, Pool},
 };
 
+mod cli;
+mod client_info;
+mod config;
 mod controllers;
 mod db;
+mod errors;
+mod impersonation;
+mod mailer;
 mod models;
 mod repositories;
+mod shutdown;
+mod tasks;
 mod templates;
+mod tokens;
+mod totp;
+mod validation;
 
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
 
@@ -27,6 +50,12 @@ pub type PgPool = Pool<ConnectionManager<PgConnection>>;
 #[derive(Clone)]
 pub struct AppState {
     pool: PgPool,
+    config: Arc<Config>,
+    session_store: PostgresStore,
+    mailer: Arc<dyn Mailer>,
+    totp_cipher: SecretCipher,
+    /// Work handlers start in the background, awaited before shutting down.
+    tasks: TaskTracker,
 }
 
 #[tokio::main]
@@ -34,90 +63,182 @@ async fn main() {
     dotenv::dotenv().ok();
     tracing_subscriber::fmt::init();
 
-    info!("🚀 Server starting...");
+    let command = Command::from_args();
 
-    let app_environment = env::var("APP_ENVIRONMENT").unwrap_or("development".to_string());
-    let app_host = env::var("APP_HOST").unwrap_or("0.0.0.0".to_string());
-    let app_port = env::var("APP_PORT").unwrap_or("80".to_string());
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    match command {
+        Command::Serve => serve(config).await,
+        Command::Migrate(command) => {
+            tokio::task::spawn_blocking(move || cli::migrate(&config, command))
+                .await
+                .unwrap()
+        }
+        Command::Roles(command) => {
+            tokio::task::spawn_blocking(move || cli::roles(&config, command))
+                .await
+                .unwrap()
+        }
+    }
+}
+
+async fn serve(config: Config) {
+    info!("🚀 Server starting...");
 
     info!(
         "Server configured to accept connections on host {}...",
-        app_host
+        config.server.host
     );
     info!(
         "Server configured to listen connections on port {}...",
-        app_port
+        config.server.port
     );
 
-    match app_environment.as_str() {
-        "development" => {
+    match config.environment {
+        Environment::Development => {
             info!("Running in development mode");
         }
-        "production" => {
+        Environment::Production => {
             info!("Running in production mode");
         }
-        _ => {
-            info!("Running in development mode");
-        }
     }
 
     let db_pool = PgPool::builder()
-        .max_size(5)
+        .max_size(config.database.pool_size)
         .build(ConnectionManager::<PgConnection>::new(
-            env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+            config.database.url.clone(),
         ))
         .expect("Failed to create pool.");
 
-    let state = AppState { pool: db_pool.clone() };
+    if config.database.migrate_on_boot {
+        let pool = db_pool.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut connection = pool.get()?;
+            db::run_pending_migrations(&mut connection)
+        })
+        .await
+        .unwrap();
+
+        match result {
+            Ok(applied) => {
+                for version in applied {
+                    info!("Applied migration {}", version);
+                }
+            }
+            Err(err) => {
+                error!("Failed to run migrations: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let session_store = PostgresStore::new(db_pool.clone());
+    let mut session_store = PostgresStore::new(db_pool.clone());
+    if let Some(schema_name) = &config.session.schema_name {
+        session_store = session_store
+            .with_schema_name(schema_name)
+            .expect("session schema name is validated by Config");
+    }
+    if let Some(table_name) = &config.session.table_name {
+        session_store = session_store
+            .with_table_name(table_name)
+            .expect("session table name is validated by Config");
+    }
+    session_store
+        .migrate()
+        .await
+        .expect("Failed to migrate the session store.");
+
+    let shutdown = Shutdown::listen();
+
+    tasks::session_reaper::spawn(
+        &shutdown,
+        session_store.clone(),
+        std::time::Duration::from_secs(config.session.reaper_interval_secs),
+        config.session.reaper_batch_size,
+    );
+    tasks::account_purger::spawn(
+        &shutdown,
+        db_pool.clone(),
+        session_store.clone(),
+        std::time::Duration::from_secs(config.auth.purge_interval_secs),
+        chrono::Duration::days(config.auth.deletion_grace_days),
+        chrono::Duration::seconds(config.auth.lockout_max_secs),
+    );
 
-    let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(false)
-        .with_expiry(Expiry::OnInactivity(Duration::days(1)));
+    let session_layer = SessionManagerLayer::new(session_store.clone())
+        .with_secure(config.session_secure())
+        .with_expiry(Expiry::OnInactivity(Duration::seconds(
+            config.session.expiry_secs,
+        )));
 
     // Auth service.
     //
     // This combines the session layer with our backend to establish the auth
     // service which will provide the auth session as a request extension.
-    let backend = Backend::new(db_pool.clone());
-    let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer).build();
+    let totp_cipher = SecretCipher::new(&config.auth.totp_key());
+    let backend = Backend::new(db_pool.clone())
+        .with_verified_email_required(config.auth.require_verified_email)
+        .with_lockout(config.auth.account_lockout(), config.auth.ip_lockout())
+        .with_totp_cipher(totp_cipher.clone());
+    let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer)
+        .with_data_key(AUTH_DATA_KEY)
+        .build();
+
+    let state = AppState {
+        pool: db_pool.clone(),
+        config: Arc::new(config.clone()),
+        session_store,
+        mailer: mailer::from_config(&config.mail),
+        totp_cipher,
+        tasks: shutdown.tasks.clone(),
+    };
 
     let app = Router::new()
         .merge(home_controller::router())
         .merge(auth_controller::router())
+        .merge(service_controller::router())
+        .merge(account_controller::router())
+        .merge(password_reset_controller::router())
+        .merge(verification_controller::router())
+        .merge(unlock_controller::router())
+        .merge(two_factor_controller::router())
+        .merge(admin_controller::router())
         // .route_layer(login_required!(Backend, login_url = "/login"))
+        .layer(axum::middleware::from_fn_with_state(
+            state.session_store.clone(),
+            client_info::track_client,
+        ))
         .layer(auth_layer)
+        .layer(axum::middleware::from_fn(errors::render_errors))
         .with_state(state);
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    // run our app with hyper, listening on the configured host and port
+    let listener = tokio::net::TcpListener::bind((config.server.host.as_str(), config.server.port))
+        .await
+        .unwrap();
+
+    let drain_timeout = std::time::Duration::from_secs(config.server.shutdown_timeout_secs);
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    ).with_graceful_shutdown(shutdown.token.clone().cancelled_owned());
+
+    // Once the signal arrives the server stops accepting connections and waits
+    // for in-flight requests, but only for as long as the drain timeout.
+    tokio::select! {
+        result = server.into_future() => result.unwrap(),
+        _ = shutdown.drain_deadline(drain_timeout) => {
+            warn!("Connections still open after {:?}, dropping them", drain_timeout);
+        }
+    }
 
-    axum::serve(listener, app).await.unwrap();
-}
+    shutdown.wait_for_tasks(drain_timeout).await;
 
-// async fn shutdown_signal() {
-//     let ctrl_c = async {
-//         signal::ctrl_c()
-//             .await
-//             .expect("failed to install Ctrl+C handler");
-//     };
-
-//     #[cfg(unix)]
-//     let terminate = async {
-//         signal::unix::signal(signal::unix::SignalKind::terminate())
-//             .expect("failed to install signal handler")
-//             .recv()
-//             .await;
-//     };
-
-//     #[cfg(not(unix))]
-//     let terminate = std::future::pending::<()>();
-
-//     tokio::select! {
-//         _ = ctrl_c => {},
-//         _ = terminate => {},
-//     }
-
-//     info!("signal received, starting graceful shutdown");
-// }
+    info!("Server stopped");
+}
@@ -0,0 +1,128 @@
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+/// A security relevant thing that happened, kept in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditAction {
+    LoginSucceeded,
+    LoginFailed,
+    TwoFactorFailed,
+    Logout,
+    LogoutOthers,
+    Registered,
+    PasswordChanged,
+    PasswordReset,
+    EmailChanged,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    DataExported,
+    AccountDeleted,
+    AccountRestored,
+    AccountPurged,
+    UserDisabled,
+    UserEnabled,
+    PasswordResetForced,
+    RoleGranted,
+    RoleRevoked,
+    ImpersonationStarted,
+    ImpersonationEnded,
+}
+
+impl AuditAction {
+    pub const ALL: [AuditAction; 22] = [
+        AuditAction::LoginSucceeded,
+        AuditAction::LoginFailed,
+        AuditAction::TwoFactorFailed,
+        AuditAction::Logout,
+        AuditAction::LogoutOthers,
+        AuditAction::Registered,
+        AuditAction::PasswordChanged,
+        AuditAction::PasswordReset,
+        AuditAction::EmailChanged,
+        AuditAction::TwoFactorEnabled,
+        AuditAction::TwoFactorDisabled,
+        AuditAction::DataExported,
+        AuditAction::AccountDeleted,
+        AuditAction::AccountRestored,
+        AuditAction::AccountPurged,
+        AuditAction::UserDisabled,
+        AuditAction::UserEnabled,
+        AuditAction::PasswordResetForced,
+        AuditAction::RoleGranted,
+        AuditAction::RoleRevoked,
+        AuditAction::ImpersonationStarted,
+        AuditAction::ImpersonationEnded,
+    ];
+
+    /// The value stored in `audit_events.action`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::LoginSucceeded => "login_succeeded",
+            AuditAction::LoginFailed => "login_failed",
+            AuditAction::TwoFactorFailed => "two_factor_failed",
+            AuditAction::Logout => "logout",
+            AuditAction::LogoutOthers => "logout_others",
+            AuditAction::Registered => "registered",
+            AuditAction::PasswordChanged => "password_changed",
+            AuditAction::PasswordReset => "password_reset",
+            AuditAction::EmailChanged => "email_changed",
+            AuditAction::TwoFactorEnabled => "two_factor_enabled",
+            AuditAction::TwoFactorDisabled => "two_factor_disabled",
+            AuditAction::DataExported => "data_exported",
+            AuditAction::AccountDeleted => "account_deleted",
+            AuditAction::AccountRestored => "account_restored",
+            AuditAction::AccountPurged => "account_purged",
+            AuditAction::UserDisabled => "user_disabled",
+            AuditAction::UserEnabled => "user_enabled",
+            AuditAction::PasswordResetForced => "password_reset_forced",
+            AuditAction::RoleGranted => "role_granted",
+            AuditAction::RoleRevoked => "role_revoked",
+            AuditAction::ImpersonationStarted => "impersonation_started",
+            AuditAction::ImpersonationEnded => "impersonation_ended",
+        }
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        AuditAction::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == action)
+            .ok_or_else(|| format!("unknown audit action: {}", action))
+    }
+}
+
+/// An audit event with the names of the users involved, as far as they still
+/// exist.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEventModel {
+    pub id: Uuid,
+    pub actor_id: Option<Uuid>,
+    pub actor_email: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub target_email: Option<String>,
+    /// Stored as text, so actions this build doesn't know still show.
+    pub action: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    /// The JSON metadata as compact text.
+    pub metadata: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_round_trip_through_their_names() {
+        for action in AuditAction::ALL {
+            assert_eq!(action.as_str().parse::<AuditAction>(), Ok(action));
+        }
+        assert!("root_shell".parse::<AuditAction>().is_err());
+    }
+}
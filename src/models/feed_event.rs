@@ -0,0 +1,57 @@
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+use super::user::UserModel;
+
+/// Something a user did that shows up in everyone's feed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeedEventKind {
+    TodoCreated,
+    TodoCompleted,
+    ProfileUpdated,
+}
+
+impl FeedEventKind {
+    /// The value stored in `feed_events.kind`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FeedEventKind::TodoCreated => "todo_created",
+            FeedEventKind::TodoCompleted => "todo_completed",
+            FeedEventKind::ProfileUpdated => "profile_updated",
+        }
+    }
+
+    /// Human readable verb used by the feed template.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            FeedEventKind::TodoCreated => "added a todo",
+            FeedEventKind::TodoCompleted => "completed a todo",
+            FeedEventKind::ProfileUpdated => "updated their profile",
+        }
+    }
+}
+
+impl FromStr for FeedEventKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "todo_created" => Ok(FeedEventKind::TodoCreated),
+            "todo_completed" => Ok(FeedEventKind::TodoCompleted),
+            "profile_updated" => Ok(FeedEventKind::ProfileUpdated),
+            _ => Err(format!("unknown feed event kind: {}", kind)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeedEventModel {
+    pub id: Uuid,
+    pub user: UserModel,
+    pub kind: FeedEventKind,
+    /// Empty unless the event is the viewer's own.
+    pub subject: String,
+    pub created_at: NaiveDateTime,
+}
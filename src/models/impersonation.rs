@@ -0,0 +1,9 @@
+use chrono::NaiveDateTime;
+
+/// A time an admin logged in as the user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImpersonationModel {
+    pub admin_name: String,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+}
@@ -1 +1,8 @@
+pub mod audit_event;
+pub mod feed_event;
+pub mod impersonation;
+pub mod page;
+pub mod permission;
+pub mod session;
+pub mod todo;
 pub mod user;
\ No newline at end of file
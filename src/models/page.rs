@@ -0,0 +1,20 @@
+/// One page of a longer list, from offset or keyset pagination.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// How many items there are on all pages together.
+    pub total: i64,
+    pub per_page: i64,
+    /// Starts at 1. `None` for keyset pages, they don't know where they are.
+    pub number: Option<i64>,
+    /// Gets the following page as the `after` of the next query, `None` on
+    /// the last page.
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// At least 1, an empty list still has an empty page.
+    pub fn pages(&self) -> i64 {
+        ((self.total + self.per_page - 1) / self.per_page.max(1)).max(1)
+    }
+}
@@ -0,0 +1,22 @@
+/// Lets admins see every user.
+pub const USERS_VIEW: &str = "users.view";
+/// Lets admins change other users and their roles, and log in as them.
+pub const USERS_MANAGE: &str = "users.manage";
+/// Lets admins read the audit log.
+pub const AUDIT_VIEW: &str = "audit.view";
+
+/// A permission name from the `permissions` table, granted to users through
+/// their roles.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Permission {
+    pub name: String,
+}
+
+/// So `permission_required!` takes the names as they are.
+impl From<&str> for Permission {
+    fn from(name: &str) -> Self {
+        Permission {
+            name: name.to_string(),
+        }
+    }
+}
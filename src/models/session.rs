@@ -0,0 +1,14 @@
+use chrono::{DateTime, Utc};
+
+/// One of a user's logged in devices.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionModel {
+    /// A hash of the session id, see `SessionDb::handle`.
+    pub handle: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    /// Whether this is the session viewing the page.
+    pub current: bool,
+}
@@ -0,0 +1,9 @@
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TodoModel {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub description: String,
+    pub done: bool,
+}
@@ -1,3 +1,4 @@
+use chrono::NaiveDateTime;
 use uuid::Uuid;
 
 // use crate::infra::errors::InfraError;
@@ -7,4 +8,9 @@ pub struct UserModel {
     pub id: Uuid,
     pub name: String,
     pub email: String,
+    pub created_at: NaiveDateTime,
+    pub email_verified: bool,
+    pub two_factor_enabled: bool,
+    pub disabled: bool,
+    pub deleted: bool,
 }
@@ -0,0 +1,81 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    Connection, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, RunQueryDsl,
+};
+use crate::db::schema::{account_unlock_tokens, users};
+use crate::tokens;
+use serde::Deserialize;
+use uuid::Uuid;
+use diesel::pg::PgConnection;
+
+use super::login_throttle_repository;
+
+/// How long an unlock link keeps working.
+pub const TOKEN_TTL_MINUTES: i64 = 60;
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = account_unlock_tokens)]
+pub struct NewAccountUnlockTokenDb {
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct UnlockAccountForm {
+    pub email: String,
+}
+
+/// Creates an unlock token for the user and returns it. Only its hash is
+/// stored, and older tokens of the user stop working.
+pub fn create_token(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<String, diesel::result::Error> {
+    let token = tokens::generate();
+
+    connection.transaction(|connection| {
+        diesel::delete(account_unlock_tokens::table.filter(account_unlock_tokens::user_id.eq(user_id)))
+            .execute(connection)?;
+
+        diesel::insert_into(account_unlock_tokens::table)
+            .values(NewAccountUnlockTokenDb {
+                user_id,
+                token_hash: tokens::hash(&token),
+                expires_at: Utc::now().naive_utc() + chrono::Duration::minutes(TOKEN_TTL_MINUTES),
+            })
+            .execute(connection)?;
+
+        Ok(token)
+    })
+}
+
+/// Clears the failed logins of the token owner's email and returns their id,
+/// or `None` for unknown and expired tokens. Tokens are deleted once used.
+/// Locks on the addresses the failures came from stay in place.
+pub fn unlock(
+    connection: &mut PgConnection,
+    token: &str,
+) -> Result<Option<Uuid>, diesel::result::Error> {
+    connection.transaction(|connection| {
+        let user_id = diesel::delete(
+            account_unlock_tokens::table
+                .filter(account_unlock_tokens::token_hash.eq(tokens::hash(token)))
+                .filter(account_unlock_tokens::expires_at.gt(Utc::now().naive_utc())),
+        )
+        .returning(account_unlock_tokens::user_id)
+        .get_result::<Uuid>(connection)
+        .optional()?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+
+        let email = users::table
+            .select(users::email)
+            .filter(users::id.eq(user_id))
+            .first::<String>(connection)?;
+        login_throttle_repository::clear(connection, &login_throttle_repository::account_key(&email))?;
+
+        Ok(Some(user_id))
+    })
+}
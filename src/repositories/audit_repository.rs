@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use diesel::pg::{Pg, PgConnection};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, Insertable, PgAnyJsonExpressionMethods, QueryDsl,
+    Queryable, RunQueryDsl, Selectable, SelectableHelper,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::client_info::RequestClient;
+use crate::db::schema::{audit_events, users};
+use crate::models::audit_event::{AuditAction, AuditEventModel};
+use crate::models::page::Page;
+use crate::tokens;
+use crate::validation::empty_as_none;
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = audit_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuditEventDb {
+    pub id: Uuid,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub action: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+impl AuditEventDb {
+    /// `emails` has the users still around, by id.
+    pub fn to_model(&self, emails: &HashMap<Uuid, String>) -> AuditEventModel {
+        let email = |id: Option<Uuid>| id.and_then(|id| emails.get(&id).cloned());
+
+        AuditEventModel {
+            id: self.id,
+            actor_id: self.actor_id,
+            actor_email: email(self.actor_id),
+            target_id: self.target_id,
+            target_email: email(self.target_id),
+            action: self.action.clone(),
+            ip_address: self.ip_address.clone(),
+            user_agent: self.user_agent.clone(),
+            metadata: self.metadata.to_string(),
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// An event to record, built up from the action and the request it happened
+/// in. Metadata never holds passwords, codes or tokens.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = audit_events)]
+pub struct NewAuditEventDb {
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub action: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: serde_json::Value,
+}
+
+impl NewAuditEventDb {
+    /// Whatever happens while an admin is logged in as someone else is on the
+    /// admin, so they are the actor and the impersonation is in the metadata.
+    pub fn new(action: AuditAction, client: &RequestClient) -> Self {
+        let metadata = match client.impersonator {
+            Some(impersonator) => json!({ "impersonation_id": impersonator.impersonation_id }),
+            None => json!({}),
+        };
+
+        Self {
+            actor_id: client.impersonator.map(|impersonator| impersonator.admin_id),
+            target_id: None,
+            action: action.as_str().to_string(),
+            ip_address: client.ip_address.clone(),
+            user_agent: client.user_agent.clone(),
+            metadata,
+        }
+    }
+
+    /// Keeps the impersonating admin as the actor.
+    pub fn actor(mut self, actor_id: Uuid) -> Self {
+        self.actor_id.get_or_insert(actor_id);
+        self
+    }
+
+    pub fn target(mut self, target_id: Uuid) -> Self {
+        self.target_id = Some(target_id);
+        self
+    }
+
+    /// For users acting on their own account.
+    pub fn user(self, user_id: Uuid) -> Self {
+        self.actor(user_id).target(user_id)
+    }
+
+    /// Adds to the metadata rather than replacing it, so the impersonation
+    /// stays in.
+    pub fn metadata(mut self, metadata: serde_json::Value) -> Self {
+        match (&mut self.metadata, metadata) {
+            (serde_json::Value::Object(current), serde_json::Value::Object(fields)) => {
+                current.extend(fields)
+            }
+            (current, metadata) => *current = metadata,
+        }
+        self
+    }
+}
+
+/// Stands in for an email in the metadata. Events about the same address
+/// still match up, without the log keeping the address itself.
+pub fn email_hash(email: &str) -> String {
+    tokens::hash(&email.trim().to_lowercase())
+}
+
+pub fn record(
+    connection: &mut PgConnection,
+    event: NewAuditEventDb,
+) -> Result<(), diesel::result::Error> {
+    diesel::insert_into(audit_events::table)
+        .values(event)
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Clears what the events say about a user who is being purged: the address,
+/// user agent and metadata of what they did, and the metadata of anything
+/// done to them or their email. The ids are nulled by the foreign keys once
+/// the user row goes.
+pub fn scrub_user(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    email: &str,
+) -> Result<(), diesel::result::Error> {
+    let empty = serde_json::Value::Object(Default::default());
+
+    diesel::update(audit_events::table.filter(audit_events::actor_id.eq(user_id)))
+        .set((
+            audit_events::ip_address.eq(None::<String>),
+            audit_events::user_agent.eq(None::<String>),
+            audit_events::metadata.eq(&empty),
+        ))
+        .execute(connection)?;
+
+    let mentioned = audit_events::target_id.eq(user_id).or(audit_events::metadata
+        .retrieve_as_text("email_hash")
+        .eq(email_hash(email)));
+    diesel::update(audit_events::table.filter(mentioned))
+        .set(audit_events::metadata.eq(&empty))
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// The admin audit log's query string. Empty fields don't filter.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditSearch {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub action: String,
+    /// Events the user did or had done to them.
+    #[serde(deserialize_with = "empty_as_none", skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub ip_address: String,
+    /// On or after this day.
+    #[serde(deserialize_with = "empty_as_none", skip_serializing_if = "Option::is_none")]
+    pub created_from: Option<NaiveDate>,
+    /// On or before this day.
+    #[serde(deserialize_with = "empty_as_none", skip_serializing_if = "Option::is_none")]
+    pub created_to: Option<NaiveDate>,
+    /// Starts at 1.
+    pub page: i64,
+}
+
+fn filtered_events(search: &AuditSearch) -> audit_events::BoxedQuery<'static, Pg> {
+    let mut query = audit_events::table.into_boxed();
+
+    let action = search.action.trim();
+    if !action.is_empty() {
+        query = query.filter(audit_events::action.eq(action.to_string()));
+    }
+    if let Some(user_id) = search.user_id {
+        query = query.filter(
+            audit_events::actor_id
+                .eq(user_id)
+                .or(audit_events::target_id.eq(user_id)),
+        );
+    }
+    let ip_address = search.ip_address.trim();
+    if !ip_address.is_empty() {
+        query = query.filter(audit_events::ip_address.eq(ip_address.to_string()));
+    }
+    if let Some(from) = search.created_from {
+        query = query.filter(audit_events::created_at.ge(from.and_time(NaiveTime::MIN)));
+    }
+    if let Some(to) = search.created_to.and_then(|to| to.succ_opt()) {
+        query = query.filter(audit_events::created_at.lt(to.and_time(NaiveTime::MIN)));
+    }
+
+    query
+}
+
+/// One page of the matching events, newest first.
+pub fn get_events(
+    connection: &mut PgConnection,
+    search: &AuditSearch,
+    per_page: i64,
+) -> Result<Page<AuditEventModel>, diesel::result::Error> {
+    let total = filtered_events(search).count().get_result(connection)?;
+
+    let number = search.page.max(1);
+    let events: Vec<AuditEventDb> = filtered_events(search)
+        .select(AuditEventDb::as_select())
+        .order((audit_events::created_at.desc(), audit_events::id.desc()))
+        .limit(per_page)
+        .offset((number - 1).saturating_mul(per_page))
+        .get_results(connection)?;
+
+    let user_ids: Vec<Uuid> = events
+        .iter()
+        .flat_map(|event| [event.actor_id, event.target_id])
+        .flatten()
+        .collect();
+    let emails: HashMap<Uuid, String> = users::table
+        .select((users::id, users::email))
+        .filter(users::id.eq_any(user_ids))
+        .get_results(connection)?
+        .into_iter()
+        .collect();
+
+    Ok(Page {
+        items: events.iter().map(|event| event.to_model(&emails)).collect(),
+        total,
+        per_page,
+        number: Some(number),
+        next_cursor: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use crate::impersonation::Impersonator;
+    use crate::repositories::user_repository::{self, NewUserDb};
+
+    #[test]
+    fn impersonated_events_are_on_the_admin() {
+        let impersonator = Impersonator {
+            admin_id: Uuid::new_v4(),
+            impersonation_id: Uuid::new_v4(),
+        };
+        let client = RequestClient {
+            impersonator: Some(impersonator),
+            ..Default::default()
+        };
+        let user_id = Uuid::new_v4();
+
+        let event = NewAuditEventDb::new(AuditAction::PasswordChanged, &client)
+            .user(user_id)
+            .metadata(json!({ "reason": "test" }));
+
+        assert_eq!(event.actor_id, Some(impersonator.admin_id));
+        assert_eq!(event.target_id, Some(user_id));
+        assert_eq!(
+            event.metadata,
+            json!({ "impersonation_id": impersonator.impersonation_id, "reason": "test" })
+        );
+    }
+
+    #[test]
+    fn events_are_found_by_either_user() {
+        let mut connection = test_connection();
+        let user = NewUserDb {
+            name: "Samuel".to_string(),
+            email: format!("{}@example.com", Uuid::new_v4()),
+            password: "password".to_string(),
+        };
+        let user = user_repository::create_user(&mut connection, user).unwrap();
+        let client = RequestClient {
+            ip_address: Some("192.0.2.1".to_string()),
+            user_agent: None,
+            impersonator: None,
+        };
+
+        let login = NewAuditEventDb::new(AuditAction::LoginSucceeded, &client).user(user.id);
+        record(&mut connection, login).unwrap();
+        let disabled = NewAuditEventDb::new(AuditAction::UserDisabled, &client)
+            .target(user.id)
+            .metadata(serde_json::json!({ "reason": "test" }));
+        record(&mut connection, disabled).unwrap();
+
+        let search = AuditSearch {
+            user_id: Some(user.id),
+            ..Default::default()
+        };
+        let page = get_events(&mut connection, &search, 10).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items[0].action, "user_disabled");
+        assert_eq!(page.items[0].metadata, r#"{"reason":"test"}"#);
+        assert_eq!(page.items[1].actor_email.as_deref(), Some(user.email.as_str()));
+
+        let logins = AuditSearch {
+            action: "login_succeeded".to_string(),
+            ..search
+        };
+        assert_eq!(get_events(&mut connection, &logins, 10).unwrap().total, 1);
+    }
+
+    #[test]
+    fn purged_users_are_scrubbed_from_events() {
+        let mut connection = test_connection();
+        let user = NewUserDb {
+            name: "Samuel".to_string(),
+            email: format!("{}@example.com", Uuid::new_v4()),
+            password: "password".to_string(),
+        };
+        let user = user_repository::create_user(&mut connection, user).unwrap();
+        let client = RequestClient {
+            ip_address: Some("192.0.2.2".to_string()),
+            user_agent: Some("Firefox".to_string()),
+            impersonator: None,
+        };
+
+        let login = NewAuditEventDb::new(AuditAction::LoginSucceeded, &client).user(user.id);
+        record(&mut connection, login).unwrap();
+        // An attempt by someone else at their email, with no user attached
+        let attempt = NewAuditEventDb::new(AuditAction::LoginFailed, &RequestClient::default())
+            .metadata(serde_json::json!({ "email_hash": email_hash(&user.email) }));
+        record(&mut connection, attempt).unwrap();
+
+        user_repository::mark_deleted(&mut connection, user.id).unwrap();
+        let cutoff = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(1);
+        assert!(user_repository::purge(&mut connection, user.id, cutoff).unwrap());
+
+        let events: Vec<AuditEventDb> = audit_events::table
+            .select(AuditEventDb::as_select())
+            .filter(audit_events::metadata.eq(serde_json::json!({})))
+            .filter(audit_events::actor_id.is_null())
+            .filter(audit_events::created_at.ge(user.created_at))
+            .get_results(&mut connection)
+            .unwrap();
+        assert!(events.iter().any(|event| event.action == "login_succeeded"
+            && event.ip_address.is_none()
+            && event.user_agent.is_none()));
+        assert!(events.iter().any(|event| event.action == "login_failed"));
+        let mentioned = audit_events::table
+            .filter(audit_events::metadata.retrieve_as_text("email_hash").eq(email_hash(&user.email)))
+            .count()
+            .get_result::<i64>(&mut connection)
+            .unwrap();
+        assert_eq!(mentioned, 0);
+    }
+}
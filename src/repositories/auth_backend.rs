@@ -1,80 +1,388 @@
 use async_trait::async_trait;
-use axum_login::AuthnBackend;
-use diesel::{
-    result::Error, ExpressionMethods, Insertable, QueryDsl, Queryable, RunQueryDsl, Selectable,
-    SelectableHelper,
-};
-// use diesel::sql_types::Uuid;
-use crate::db::schema::users;
-use crate::models::user::UserModel;
+use axum_login::{AuthnBackend, AuthzBackend};
+use crate::client_info::RequestClient;
+use crate::config::AuthConfig;
+use crate::errors::AppError;
+use crate::models::audit_event::AuditAction;
+use crate::models::permission::Permission;
+use crate::totp::{self, SecretCipher};
 use crate::PgPool;
-use password_auth::verify_password;
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-// use crate::infra::errors::{adapt_infra_error, InfraError};
 use diesel::pg::PgConnection;
+use log::{error, info};
+use serde_json::json;
+use password_auth::{generate_hash, verify_password};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use uuid::Uuid;
 
+use super::audit_repository::{self, NewAuditEventDb};
+use super::login_throttle_repository::{self, LockoutPolicy};
+use super::role_repository;
+use super::two_factor_repository;
 use super::user_repository::{self, Credentials, UserDb};
 
+/// The session key axum-login keeps the logged in user under. `PostgresStore`
+/// reads it to know whose session it is saving.
+pub const AUTH_DATA_KEY: &str = "axum-login.data";
+
 #[derive(Debug, Clone)]
 pub struct Backend {
     pool: PgPool,
+    require_verified_email: bool,
+    account_lockout: LockoutPolicy,
+    ip_lockout: LockoutPolicy,
+    totp_cipher: SecretCipher,
 }
 
 impl Backend {
     /// Create a new Backend for axum login auth with the provided connection pool.
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        // Hash it now so the first unknown email isn't slower than the rest
+        dummy_hash();
+
+        Self {
+            pool,
+            require_verified_email: false,
+            account_lockout: AuthConfig::default().account_lockout(),
+            ip_lockout: AuthConfig::default().ip_lockout(),
+            totp_cipher: SecretCipher::new(&AuthConfig::default().totp_key()),
+        }
+    }
+
+    /// Decrypts the TOTP secrets of two-factor authentication.
+    pub fn with_totp_cipher(mut self, totp_cipher: SecretCipher) -> Self {
+        self.totp_cipher = totp_cipher;
+        self
+    }
+
+    /// How failed logins lock the email they were for and the address they
+    /// came from.
+    pub fn with_lockout(mut self, account: LockoutPolicy, ip: LockoutPolicy) -> Self {
+        self.account_lockout = account;
+        self.ip_lockout = ip;
+        self
+    }
+
+    /// Whether logged in users with an unverified email are kept out of the
+    /// routes behind `verified_email_required`.
+    pub fn with_verified_email_required(mut self, required: bool) -> Self {
+        self.require_verified_email = required;
+        self
+    }
+
+    pub fn requires_verified_email(&self) -> bool {
+        self.require_verified_email
+    }
+
+    /// Checks the second factor of a user with two-factor authentication, a
+    /// code from their authenticator app or one of their recovery codes. Both
+    /// are used up by a successful check. Wrong codes lock the account and the
+    /// address like wrong passwords do, and only a right code clears the
+    /// account's failures.
+    pub async fn verify_second_factor(
+        &self,
+        user: &UserDb,
+        code: String,
+        ip_address: Option<String>,
+    ) -> Result<bool, AppError> {
+        let mut connection = self.pool.get()?;
+        let (account_lockout, ip_lockout) = (self.account_lockout, self.ip_lockout);
+        let totp_cipher = self.totp_cipher.clone();
+        let (user_id, account_key) = (user.id, login_throttle_repository::account_key(&user.email));
+        let ip_key = ip_address.as_deref().map(login_throttle_repository::ip_key);
+
+        tokio::task::spawn_blocking(move || {
+            let keys: Vec<String> = [Some(account_key.clone()), ip_key.clone()]
+                .into_iter()
+                .flatten()
+                .collect();
+            check_unlocked(&mut connection, &keys)?;
+
+            let totp = two_factor_repository::get_totp(&mut connection, user_id)?;
+            if totp.totp_enabled_at.is_none() {
+                return Ok(false);
+            }
+            let secret = totp.totp_secret.and_then(|sealed| {
+                let secret = totp_cipher.decrypt(user_id, &sealed);
+                if secret.is_none() {
+                    // Recovery codes still work, so the user isn't locked out
+                    error!("TOTP secret of user {} doesn't decrypt, was the key changed?", user_id);
+                }
+                secret
+            });
+            let now = chrono::Utc::now().timestamp();
+
+            let verified = match secret
+                .and_then(|secret| totp::verify(&secret, &code, now, totp.totp_last_step))
+            {
+                Some(step) => two_factor_repository::use_step(&mut connection, user_id, step)?,
+                None => two_factor_repository::use_recovery_code(&mut connection, user_id, &code)?,
+            };
+
+            if verified {
+                login_throttle_repository::clear(&mut connection, &account_key)?;
+            } else {
+                login_throttle_repository::record_failure(&mut connection, account_key, account_lockout)?;
+                if let Some(ip_key) = ip_key {
+                    login_throttle_repository::record_failure(&mut connection, ip_key, ip_lockout)?;
+                }
+            }
+
+            Ok(verified)
+        })
+        .await?
+    }
+
+    /// The user of a login that got past the password, deleted or not. Unlike
+    /// `get_user`, which logs deleted users out.
+    pub async fn get_pending_user(&self, user_id: Uuid) -> Result<Option<UserDb>, AppError> {
+        let mut connection = self.pool.get()?;
+
+        let user = tokio::task::spawn_blocking(move || {
+            user_repository::get_by_id(&mut connection, user_id)
+        })
+        .await?;
+
+        match user {
+            Ok(user) if user.disabled_at.is_some() => Ok(None),
+            Ok(user) => Ok(Some(user)),
+            Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Logging in during the grace period cancels the deletion. Call it once
+    /// the login is complete, after the second factor too.
+    pub async fn restore_if_deleted(
+        &self,
+        user: &mut UserDb,
+        client: &RequestClient,
+    ) -> Result<(), AppError> {
+        if user.deleted_at.is_none() {
+            return Ok(());
+        }
+
+        let mut connection = self.pool.get()?;
+        let user_id = user.id;
+        let restored = NewAuditEventDb::new(AuditAction::AccountRestored, client).user(user_id);
+        tokio::task::spawn_blocking(move || {
+            user_repository::restore(&mut connection, user_id)?;
+            audit_repository::record(&mut connection, restored)
+        })
+        .await??;
+
+        user.deleted_at = None;
+        info!("Restored deleted user {}", user_id);
+
+        Ok(())
+    }
+
+    /// Writes an event to the audit log.
+    pub async fn audit(&self, event: NewAuditEventDb) -> Result<(), AppError> {
+        let mut connection = self.pool.get()?;
+
+        tokio::task::spawn_blocking(move || audit_repository::record(&mut connection, event)).await??;
+
+        Ok(())
+    }
+
+    /// Checks the password of a logged in user before changes to the account.
+    /// Wrong passwords count towards the lockout like failed logins do.
+    pub async fn verify_current_password(
+        &self,
+        user: &UserDb,
+        password: String,
+    ) -> Result<bool, AppError> {
+        let mut connection = self.pool.get()?;
+        let account_lockout = self.account_lockout;
+        let hash = user.password.clone();
+        let account_key = login_throttle_repository::account_key(&user.email);
+
+        tokio::task::spawn_blocking(move || {
+            check_unlocked(&mut connection, std::slice::from_ref(&account_key))?;
+
+            let verified = verify_password(&password, &hash).is_ok();
+            if verified {
+                login_throttle_repository::clear(&mut connection, &account_key)?;
+            } else {
+                login_throttle_repository::record_failure(&mut connection, account_key, account_lockout)?;
+            }
+
+            Ok(verified)
+        })
+        .await?
     }
 }
 
+/// Locked accounts and addresses can't do anything that needs a password or
+/// code either, until the lockout ends.
+fn check_unlocked(connection: &mut PgConnection, keys: &[String]) -> Result<(), AppError> {
+    if let Some(locked_until) = login_throttle_repository::locked_until(connection, keys)? {
+        return Err(AppError::locked_until(locked_until));
+    }
+
+    Ok(())
+}
+
 #[async_trait]
 impl AuthnBackend for Backend {
     type User = UserDb;
     type Credentials = Credentials;
-    type Error = Error;
+    type Error = AppError;
 
     async fn authenticate(
         &self,
         creds: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
-        let mut connection = self.pool.get().unwrap();
+        let mut connection = self.pool.get()?;
+        let (account_lockout, ip_lockout) = (self.account_lockout, self.ip_lockout);
 
-        let user = tokio::task::spawn_blocking(move || {
-            user_repository::get_by_email(&mut connection, creds.email.to_string())
-        })
-        .await
-        .unwrap()
-        .unwrap();
+        // Hashing is slow on purpose, so it runs on the blocking pool too
+        tokio::task::spawn_blocking(move || {
+            let account_key = login_throttle_repository::account_key(&creds.email);
+            let ip_key = creds.ip_address.as_deref().map(login_throttle_repository::ip_key);
+            let keys: Vec<String> = [Some(account_key.clone()), ip_key.clone()]
+                .into_iter()
+                .flatten()
+                .collect();
+            let client = RequestClient {
+                ip_address: creds.ip_address.clone(),
+                user_agent: creds.user_agent.clone(),
+                impersonator: None,
+            };
+            // The email may not have an account, its hash still matches up
+            // the attempts
+            let failed = |reason: &str| {
+                let email_hash = audit_repository::email_hash(&creds.email);
+                NewAuditEventDb::new(AuditAction::LoginFailed, &client)
+                    .metadata(json!({ "email_hash": email_hash, "reason": reason }))
+            };
 
-        let verify_result = verify_password(creds.password, &user.password)
-            .ok()
-            .is_some();
+            // Locked keys don't get to try a password at all
+            if let Some(locked_until) = login_throttle_repository::locked_until(&mut connection, &keys)? {
+                audit_repository::record(&mut connection, failed("locked"))?;
+                return Err(AppError::locked_until(locked_until));
+            }
 
-        match verify_result {
-            true => Ok(Some(user)),
-            false => Ok(None),
-        }
+            let user = match user_repository::get_by_email(&mut connection, creds.email.clone()) {
+                Ok(user) => Some(user),
+                // An unknown email is a failed login, not an error. We still
+                // verify a password so it takes as long as a wrong password
+                // and doesn't reveal which emails have an account.
+                Err(diesel::result::Error::NotFound) => {
+                    let _ = verify_password(&creds.password, dummy_hash());
+                    None
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            match user {
+                Some(user) if verify_password(&creds.password, &user.password).is_ok() => {
+                    // With two-factor authentication the failures stay until
+                    // the code checks out, or logging in again would reset
+                    // the count of wrong codes
+                    if user.totp_enabled_at.is_none() {
+                        login_throttle_repository::clear(&mut connection, &account_key)?;
+                    }
+                    if user.disabled_at.is_some() {
+                        audit_repository::record(&mut connection, failed("disabled").user(user.id))?;
+                        return Err(AppError::AccountDisabled);
+                    }
+                    Ok(Some(user))
+                }
+                // Unknown emails count as failures too, they lock the same way
+                user => {
+                    login_throttle_repository::record_failure(
+                        &mut connection,
+                        account_key,
+                        account_lockout,
+                    )?;
+                    if let Some(ip_key) = ip_key {
+                        login_throttle_repository::record_failure(&mut connection, ip_key, ip_lockout)?;
+                    }
+                    let event = match user {
+                        Some(user) => failed("wrong_password").user(user.id),
+                        None => failed("unknown_email"),
+                    };
+                    audit_repository::record(&mut connection, event)?;
+                    Ok(None)
+                }
+            }
+        })
+        .await?
     }
 
     async fn get_user(&self, user_id: &Uuid) -> Result<Option<UserDb>, Self::Error> {
-        let mut connection = self.pool.get().unwrap();
+        let mut connection = self.pool.get()?;
 
-        let user_id = user_id.clone();
+        let user_id = *user_id;
         let user = tokio::task::spawn_blocking(move || {
             user_repository::get_by_id(&mut connection, user_id)
         })
-        .await
-        .unwrap();
+        .await?;
 
+        // Disabled and deleted users are logged out by their next request
         match user {
+            Ok(user) if user.disabled_at.is_some() || user.deleted_at.is_some() => Ok(None),
             Ok(user) => Ok(Some(user)),
-            Err(_) => Ok(None),
+            Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(err) => Err(err.into()),
         }
     }
 }
 
+/// Roles are the groups of axum-login, users only get permissions through
+/// them.
+#[async_trait]
+impl AuthzBackend for Backend {
+    type Permission = Permission;
+
+    async fn get_group_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        let mut connection = self.pool.get()?;
+
+        let user_id = user.id;
+        let names = tokio::task::spawn_blocking(move || {
+            role_repository::get_permissions_by_user_id(&mut connection, user_id)
+        })
+        .await??;
+
+        Ok(names.into_iter().map(|name| Permission { name }).collect())
+    }
+}
+
+/// A hash made with the same parameters as real passwords, checked against
+/// when the email is unknown.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    DUMMY_HASH.get_or_init(|| generate_hash("not the password of anyone"))
+}
+
 // We use a type alias for convenience.
 //
 // Note that we've supplied our concrete backend here.
 pub type AuthSession = axum_login::AuthSession<Backend>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn overlong_unknown_emails_are_invalid_credentials() {
+        let backend = Backend::new(test_pool());
+        let creds = Credentials {
+            email: format!("{}@example.com", "a".repeat(388)),
+            password: "password".to_string(),
+            next: None,
+            ip_address: None,
+            user_agent: None,
+        };
+        assert_eq!(creds.email.len(), 400);
+
+        assert!(backend.authenticate(creds).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::result::{DatabaseErrorKind, Error};
+use diesel::{
+    Connection, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, RunQueryDsl,
+};
+use crate::db::schema::email_verification_tokens;
+use crate::tokens;
+use uuid::Uuid;
+use diesel::pg::PgConnection;
+
+use super::user_repository;
+
+/// How long a verification link keeps working. A new one can be requested
+/// from the verification page.
+pub const TOKEN_TTL_HOURS: i64 = 24;
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = email_verification_tokens)]
+pub struct NewEmailVerificationTokenDb {
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub new_email: Option<String>,
+}
+
+/// What following a verification link did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Verified {
+    pub user_id: Uuid,
+    /// The email before, when the link was for an email change.
+    pub previous_email: Option<String>,
+    pub email: String,
+}
+
+/// Creates a verification token for the user's email and returns it. Only its
+/// hash is stored, and older tokens for the email stop working.
+pub fn create_token(connection: &mut PgConnection, user_id: Uuid) -> Result<String, Error> {
+    insert_token(connection, user_id, None)
+}
+
+/// Creates a token that changes the user's email to `new_email` once it is
+/// verified, replacing any earlier pending change.
+pub fn create_email_change_token(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    new_email: String,
+) -> Result<String, Error> {
+    insert_token(connection, user_id, Some(new_email))
+}
+
+/// Verifying the current email and changing it are pending separately, so
+/// neither replaces the other's token.
+fn insert_token(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    new_email: Option<String>,
+) -> Result<String, Error> {
+    let token = tokens::generate();
+
+    connection.transaction(|connection| {
+        let tokens = email_verification_tokens::table
+            .filter(email_verification_tokens::user_id.eq(user_id));
+        match new_email {
+            Some(_) => diesel::delete(tokens.filter(email_verification_tokens::new_email.is_not_null()))
+                .execute(connection)?,
+            None => diesel::delete(tokens.filter(email_verification_tokens::new_email.is_null()))
+                .execute(connection)?,
+        };
+
+        diesel::insert_into(email_verification_tokens::table)
+            .values(NewEmailVerificationTokenDb {
+                user_id,
+                token_hash: tokens::hash(&token),
+                expires_at: Utc::now().naive_utc() + chrono::Duration::hours(TOKEN_TTL_HOURS),
+                new_email,
+            })
+            .execute(connection)?;
+
+        Ok(token)
+    })
+}
+
+/// Marks the email of the token verified, switching to the new email first
+/// for an email change. Returns `None` for unknown and expired tokens, and for
+/// changes to an email that got registered in the meantime. Tokens are deleted
+/// once used, and every other token of the user once the email changed, as
+/// they were sent to the old one.
+pub fn verify(connection: &mut PgConnection, token: &str) -> Result<Option<Verified>, Error> {
+    connection.transaction(|connection| {
+        let used = diesel::delete(
+            email_verification_tokens::table
+                .filter(email_verification_tokens::token_hash.eq(tokens::hash(token)))
+                .filter(email_verification_tokens::expires_at.gt(Utc::now().naive_utc())),
+        )
+        .returning((email_verification_tokens::user_id, email_verification_tokens::new_email))
+        .get_result::<(Uuid, Option<String>)>(connection)
+        .optional()?;
+        let Some((user_id, new_email)) = used else {
+            return Ok(None);
+        };
+        let user = user_repository::get_by_id(connection, user_id)?;
+
+        let Some(new_email) = new_email else {
+            user_repository::mark_email_verified(connection, user_id)?;
+            return Ok(Some(Verified {
+                user_id,
+                previous_email: None,
+                email: user.email,
+            }));
+        };
+
+        // A savepoint, so the token stays used when the email is taken
+        let changed = connection.transaction(|connection| {
+            let changed = user_repository::update_email(connection, user_id, new_email)?;
+            diesel::delete(
+                email_verification_tokens::table.filter(email_verification_tokens::user_id.eq(user_id)),
+            )
+            .execute(connection)?;
+
+            Ok(changed)
+        });
+        match changed {
+            Ok(changed) => Ok(Some(Verified {
+                user_id,
+                previous_email: Some(user.email),
+                email: changed.email,
+            })),
+            Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use crate::repositories::user_repository::NewUserDb;
+
+    #[test]
+    fn verify_marks_the_user_verified_once() {
+        let mut connection = test_connection();
+        let user = user_repository::create_user(
+            &mut connection,
+            NewUserDb {
+                name: "Samuel".to_string(),
+                email: format!("{}@example.com", Uuid::new_v4()),
+                password: "password".to_string(),
+            },
+        )
+        .unwrap();
+        assert_eq!(user.email_verified_at, None);
+        let replaced = create_token(&mut connection, user.id).unwrap();
+        let token = create_token(&mut connection, user.id).unwrap();
+
+        assert_eq!(verify(&mut connection, &replaced).unwrap(), None);
+        let verified = verify(&mut connection, &token).unwrap();
+        assert_eq!(verified.map(|verified| verified.user_id), Some(user.id));
+        assert_eq!(verify(&mut connection, &token).unwrap(), None);
+
+        let user = user_repository::get_by_id(&mut connection, user.id).unwrap();
+        assert!(user.email_verified_at.is_some());
+    }
+
+    #[test]
+    fn email_changes_wait_for_the_new_email_to_be_verified() {
+        let mut connection = test_connection();
+        let mut create = |email: String| {
+            let user = NewUserDb {
+                name: "Samuel".to_string(),
+                email,
+                password: "password".to_string(),
+            };
+            user_repository::create_user(&mut connection, user).unwrap()
+        };
+        let user = create(format!("{}@example.com", Uuid::new_v4()));
+        let other = create(format!("{}@example.com", Uuid::new_v4()));
+        let new_email = format!("{}@example.com", Uuid::new_v4());
+
+        let verification = create_token(&mut connection, user.id).unwrap();
+        let token = create_email_change_token(&mut connection, user.id, new_email.clone()).unwrap();
+        let user = user_repository::get_by_id(&mut connection, user.id).unwrap();
+        assert_ne!(user.email, new_email);
+
+        let verified = verify(&mut connection, &token).unwrap().unwrap();
+        assert_eq!(verified.previous_email.as_deref(), Some(user.email.as_str()));
+        assert_eq!(verified.email, new_email);
+        let changed = user_repository::get_by_id(&mut connection, user.id).unwrap();
+        assert_eq!(changed.email, new_email);
+        assert!(changed.email_verified_at.is_some());
+        // The link sent to the old email can't verify the new one
+        assert_eq!(verify(&mut connection, &verification).unwrap(), None);
+
+        let taken = create_email_change_token(&mut connection, user.id, other.email).unwrap();
+        assert_eq!(verify(&mut connection, &taken).unwrap(), None);
+        assert_eq!(user_repository::get_by_id(&mut connection, user.id).unwrap().email, new_email);
+    }
+}
@@ -0,0 +1,176 @@
+use std::io::{Cursor, Write};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use serde::Serialize;
+use uuid::Uuid;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use super::feed_repository::{self, FeedEventDb};
+use super::impersonation_repository;
+use super::role_repository;
+use super::session_repository::SessionDb;
+use super::todo_repository::{self, TodoDb};
+use super::user_repository;
+
+/// Everything stored about a user, for them to download. Password hashes,
+/// TOTP secrets and session ids stay out, they are credentials rather than
+/// data about the user.
+#[derive(Serialize)]
+pub struct UserExport {
+    pub exported_at: NaiveDateTime,
+    pub profile: ProfileExport,
+    pub roles: Vec<String>,
+    pub todos: Vec<TodoDb>,
+    pub feed_events: Vec<FeedEventDb>,
+    pub sessions: Vec<SessionExport>,
+    /// Times admins logged in as the user.
+    pub impersonations: Vec<ImpersonationExport>,
+}
+
+#[derive(Serialize)]
+pub struct ProfileExport {
+    pub id: Uuid,
+    pub name: String,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub two_factor_enabled_at: Option<NaiveDateTime>,
+    pub disabled_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct SessionExport {
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl From<&SessionDb> for SessionExport {
+    fn from(session: &SessionDb) -> Self {
+        Self {
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            ip_address: session.ip_address.clone(),
+            user_agent: session.user_agent.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ImpersonationExport {
+    pub admin_name: String,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+/// Everything in the database tied to the user except their sessions, which
+/// live in the session store's own table and are added by the caller.
+pub fn export_user(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<UserExport, diesel::result::Error> {
+    let user = user_repository::get_by_id(connection, user_id)?;
+    let roles = role_repository::get_by_user_id(connection, user_id)?;
+    let todos = todo_repository::get_by_user_id(connection, user_id)?;
+    let feed_events = feed_repository::get_by_user_id(connection, user_id)?;
+    // All of them, the admin page only shows the latest
+    let impersonations = impersonation_repository::get_by_user_id(connection, user_id, i64::MAX)?;
+
+    Ok(UserExport {
+        exported_at: Utc::now().naive_utc(),
+        profile: ProfileExport {
+            id: user.id,
+            name: user.name,
+            email: user.email,
+            created_at: user.created_at,
+            email_verified_at: user.email_verified_at,
+            two_factor_enabled_at: user.totp_enabled_at,
+            disabled_at: user.disabled_at,
+            deleted_at: user.deleted_at,
+        },
+        roles: roles.into_iter().map(|role| role.name).collect(),
+        todos,
+        feed_events,
+        sessions: Vec::new(),
+        impersonations: impersonations
+            .into_iter()
+            .map(|(impersonation, admin_name)| ImpersonationExport {
+                admin_name,
+                started_at: impersonation.started_at,
+                ended_at: impersonation.ended_at,
+            })
+            .collect(),
+    })
+}
+
+impl UserExport {
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("exports serialize")
+    }
+
+    /// A ZIP archive with the whole export in `informator.json`, and each
+    /// part in its own file too for people who only want one of them.
+    pub fn to_zip(&self) -> zip::result::ZipResult<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+        let parts: [(&str, serde_json::Result<Vec<u8>>); 6] = [
+            ("profile.json", serde_json::to_vec_pretty(&self.profile)),
+            ("roles.json", serde_json::to_vec_pretty(&self.roles)),
+            ("todos.json", serde_json::to_vec_pretty(&self.todos)),
+            ("feed_events.json", serde_json::to_vec_pretty(&self.feed_events)),
+            ("sessions.json", serde_json::to_vec_pretty(&self.sessions)),
+            ("impersonations.json", serde_json::to_vec_pretty(&self.impersonations)),
+        ];
+        zip.start_file("informator.json", options)?;
+        zip.write_all(&self.to_json())?;
+        for (name, json) in parts {
+            zip.start_file(name, options)?;
+            zip.write_all(&json.expect("exports serialize"))?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::db::test_connection;
+    use crate::repositories::todo_repository::NewTodoDb;
+    use crate::repositories::user_repository::NewUserDb;
+
+    #[test]
+    fn exports_hold_the_data_but_no_credentials() {
+        let mut connection = test_connection();
+        let user = NewUserDb {
+            name: "Samuel".to_string(),
+            email: format!("{}@example.com", Uuid::new_v4()),
+            password: "password".to_string(),
+        };
+        let user = user_repository::create_user(&mut connection, user).unwrap();
+        let todo = NewTodoDb {
+            user_id: user.id,
+            description: "Water the plants".to_string(),
+        };
+        todo_repository::create_todo(&mut connection, todo).unwrap();
+
+        let export = export_user(&mut connection, user.id).unwrap();
+        let json = String::from_utf8(export.to_json()).unwrap();
+        assert!(json.contains("Water the plants"));
+        assert!(!json.contains(&user.password));
+
+        let zip = export.to_zip().unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        let mut profile = String::new();
+        archive.by_name("profile.json").unwrap().read_to_string(&mut profile).unwrap();
+        assert!(profile.contains(&user.email));
+        assert!(archive.by_name("informator.json").is_ok());
+    }
+}
@@ -0,0 +1,123 @@
+use chrono::NaiveDateTime;
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper,
+};
+use crate::db::schema::{feed_events, users};
+use crate::models::feed_event::{FeedEventKind, FeedEventModel};
+use serde::Serialize;
+use uuid::Uuid;
+use diesel::pg::PgConnection;
+
+use super::user_repository::UserDb;
+
+#[derive(Serialize, Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = feed_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FeedEventDb {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub kind: String,
+    pub subject: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl FeedEventDb {
+    /// Returns `None` for event kinds this build doesn't know how to render.
+    /// Todos are private, so only the `viewer_id` user sees the subjects of
+    /// their own events; everyone else just sees what was done.
+    pub fn to_model(&self, user: &UserDb, viewer_id: Uuid) -> Option<FeedEventModel> {
+        let subject = match self.user_id == viewer_id {
+            true => self.subject.clone(),
+            false => String::new(),
+        };
+
+        Some(FeedEventModel {
+            id: self.id,
+            user: user.to_model(),
+            kind: self.kind.parse().ok()?,
+            subject,
+            created_at: self.created_at,
+        })
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = feed_events)]
+pub struct NewFeedEventDb {
+    pub user_id: Uuid,
+    pub kind: String,
+    pub subject: String,
+}
+
+pub fn create_event(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    kind: FeedEventKind,
+    subject: String,
+) -> Result<FeedEventDb, diesel::result::Error> {
+    let result = diesel::insert_into(feed_events::table)
+        .values(NewFeedEventDb {
+            user_id,
+            kind: kind.as_str().to_string(),
+            subject,
+        })
+        .returning(FeedEventDb::as_returning())
+        .get_result(connection)?;
+
+    Ok(result)
+}
+
+/// Every event of one user, oldest first.
+pub fn get_by_user_id(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<FeedEventDb>, diesel::result::Error> {
+    let result = feed_events::table
+        .select(FeedEventDb::as_select())
+        .filter(feed_events::user_id.eq(user_id))
+        .order(feed_events::created_at.asc())
+        .get_results(connection)?;
+
+    Ok(result)
+}
+
+/// Pages through the feed newest-first. `before` is the id of the last event
+/// of the previous page; the returned flag tells whether there is another page.
+pub fn get_page(
+    connection: &mut PgConnection,
+    before: Option<Uuid>,
+    limit: i64,
+) -> Result<(Vec<(FeedEventDb, UserDb)>, bool), diesel::result::Error> {
+    let mut query = feed_events::table
+        .inner_join(users::table)
+        .select((FeedEventDb::as_select(), UserDb::as_select()))
+        .order((feed_events::created_at.desc(), feed_events::id.desc()))
+        .limit(limit + 1)
+        .into_boxed();
+
+    if let Some(before) = before {
+        let cursor = feed_events::table
+            .select(feed_events::created_at)
+            .filter(feed_events::id.eq(before))
+            .first::<NaiveDateTime>(connection)
+            .optional()?;
+
+        // An unknown cursor means the event is gone, so there's nothing after it
+        let Some(cursor) = cursor else {
+            return Ok((Vec::new(), false));
+        };
+
+        query = query.filter(
+            feed_events::created_at.lt(cursor).or(feed_events::created_at
+                .eq(cursor)
+                .and(feed_events::id.lt(before))),
+        );
+    }
+
+    let mut events = query.get_results::<(FeedEventDb, UserDb)>(connection)?;
+    let has_more = events.len() as i64 > limit;
+    events.truncate(limit as usize);
+
+    Ok((events, has_more))
+}
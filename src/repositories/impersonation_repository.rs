@@ -0,0 +1,72 @@
+use chrono::NaiveDateTime;
+use diesel::{
+    ExpressionMethods, JoinOnDsl, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper,
+};
+use crate::db::schema::{impersonations, users};
+use crate::models::impersonation::ImpersonationModel;
+use uuid::Uuid;
+use diesel::pg::PgConnection;
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = impersonations)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ImpersonationDb {
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+impl ImpersonationDb {
+    pub fn to_model(&self, admin_name: &str) -> ImpersonationModel {
+        ImpersonationModel {
+            admin_name: admin_name.to_string(),
+            started_at: self.started_at,
+            ended_at: self.ended_at,
+        }
+    }
+}
+
+/// Records an admin logging in as `user_id` and returns the record's id.
+pub fn start(
+    connection: &mut PgConnection,
+    admin_id: Uuid,
+    user_id: Uuid,
+) -> Result<Uuid, diesel::result::Error> {
+    let result = diesel::insert_into(impersonations::table)
+        .values((
+            impersonations::admin_id.eq(admin_id),
+            impersonations::user_id.eq(user_id),
+        ))
+        .returning(impersonations::id)
+        .get_result(connection)?;
+
+    Ok(result)
+}
+
+pub fn end(connection: &mut PgConnection, id: Uuid) -> Result<(), diesel::result::Error> {
+    diesel::update(
+        impersonations::table
+            .filter(impersonations::id.eq(id))
+            .filter(impersonations::ended_at.is_null()),
+    )
+    .set(impersonations::ended_at.eq(diesel::dsl::now))
+    .execute(connection)?;
+
+    Ok(())
+}
+
+/// The latest times someone logged in as the user, with the admin's name.
+pub fn get_by_user_id(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    limit: i64,
+) -> Result<Vec<(ImpersonationDb, String)>, diesel::result::Error> {
+    let result = impersonations::table
+        .inner_join(users::table.on(users::id.eq(impersonations::admin_id)))
+        .select((ImpersonationDb::as_select(), users::name))
+        .filter(impersonations::user_id.eq(user_id))
+        .order(impersonations::started_at.desc())
+        .limit(limit)
+        .get_results(connection)?;
+
+    Ok(result)
+}
@@ -0,0 +1,299 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper,
+};
+use crate::db::schema::login_throttles;
+use crate::tokens;
+use diesel::pg::PgConnection;
+
+/// When a throttle key gets locked and for how long.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LockoutPolicy {
+    /// Failures allowed before the first lock.
+    pub threshold: i32,
+    /// The first lock, doubled by every further failure.
+    pub base_secs: i64,
+    /// Locks never get longer than this. Failures older than this are
+    /// forgotten too, so the count starts over.
+    pub max_secs: i64,
+}
+
+impl LockoutPolicy {
+    /// How long to lock a key after its `failures`th failure, if at all.
+    pub fn lock_secs(&self, failures: i32) -> Option<i64> {
+        let over = failures.checked_sub(self.threshold).filter(|over| *over >= 0)?;
+        let factor = 1i64.checked_shl(over.min(32) as u32).unwrap_or(i64::MAX);
+
+        Some(self.base_secs.saturating_mul(factor).min(self.max_secs))
+    }
+}
+
+/// Emails are hashed so that keys stay short however long the email is.
+fn email_part(email: &str) -> String {
+    tokens::hash(&email.trim().to_lowercase())
+}
+
+/// The throttle key of an account, by email whether or not it exists.
+pub fn account_key(email: &str) -> String {
+    format!("account:{}", email_part(email))
+}
+
+pub fn ip_key(ip_address: &str) -> String {
+    format!("ip:{}", ip_address)
+}
+
+/// Password reset requests are throttled apart from logins, so requesting
+/// resets can't lock anyone out of their account.
+pub fn password_reset_key(email: &str) -> String {
+    format!("password-reset:{}", email_part(email))
+}
+
+pub fn password_reset_ip_key(ip_address: &str) -> String {
+    format!("password-reset-ip:{}", ip_address)
+}
+
+/// Unlock requests have their own keys too, they'd lock the account otherwise.
+pub fn unlock_key(email: &str) -> String {
+    format!("unlock:{}", email_part(email))
+}
+
+pub fn unlock_ip_key(ip_address: &str) -> String {
+    format!("unlock-ip:{}", ip_address)
+}
+
+/// Resent verification emails, counted like the requests above.
+pub fn verification_key(email: &str) -> String {
+    format!("verification:{}", email_part(email))
+}
+
+pub fn verification_ip_key(ip_address: &str) -> String {
+    format!("verification-ip:{}", ip_address)
+}
+
+#[derive(Queryable, Selectable, Insertable, Clone, Debug)]
+#[diesel(table_name = login_throttles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct LoginThrottleDb {
+    pub key: String,
+    pub failures: i32,
+    pub last_failed_at: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+/// The latest lock still in force on any of `keys`.
+pub fn locked_until(
+    connection: &mut PgConnection,
+    keys: &[String],
+) -> Result<Option<NaiveDateTime>, diesel::result::Error> {
+    let result = login_throttles::table
+        .select(diesel::dsl::max(login_throttles::locked_until))
+        .filter(login_throttles::key.eq_any(keys))
+        .filter(login_throttles::locked_until.gt(Utc::now().naive_utc()))
+        .first::<Option<NaiveDateTime>>(connection)?;
+
+    Ok(result)
+}
+
+/// Counts a failed login against `key` and locks it once the policy says so.
+pub fn record_failure(
+    connection: &mut PgConnection,
+    key: String,
+    policy: LockoutPolicy,
+) -> Result<LoginThrottleDb, diesel::result::Error> {
+    connection.transaction(|connection| {
+        let now = Utc::now().naive_utc();
+        let previous = login_throttles::table
+            .select(LoginThrottleDb::as_select())
+            .filter(login_throttles::key.eq(&key))
+            .for_update()
+            .first(connection)
+            .optional()?;
+
+        let failures = match previous {
+            Some(previous)
+                if now - previous.last_failed_at < chrono::Duration::seconds(policy.max_secs) =>
+            {
+                previous.failures.saturating_add(1)
+            }
+            _ => 1,
+        };
+        let throttle = LoginThrottleDb {
+            key,
+            failures,
+            last_failed_at: now,
+            locked_until: policy
+                .lock_secs(failures)
+                .map(|secs| now + chrono::Duration::seconds(secs)),
+        };
+
+        let result = diesel::insert_into(login_throttles::table)
+            .values(&throttle)
+            .on_conflict(login_throttles::key)
+            .do_update()
+            .set((
+                login_throttles::failures.eq(throttle.failures),
+                login_throttles::last_failed_at.eq(throttle.last_failed_at),
+                login_throttles::locked_until.eq(throttle.locked_until),
+            ))
+            .returning(LoginThrottleDb::as_returning())
+            .get_result(connection)?;
+
+        Ok(result)
+    })
+}
+
+/// Counts a request that may send an email against each of `keys`, unless one
+/// of them is locked already. Returns that lock.
+pub fn count_request(
+    connection: &mut PgConnection,
+    keys: Vec<(String, LockoutPolicy)>,
+) -> Result<Option<NaiveDateTime>, diesel::result::Error> {
+    let names: Vec<String> = keys.iter().map(|(key, _)| key.clone()).collect();
+    if let Some(locked_until) = locked_until(connection, &names)? {
+        return Ok(Some(locked_until));
+    }
+
+    for (key, policy) in keys {
+        record_failure(connection, key, policy)?;
+    }
+
+    Ok(None)
+}
+
+/// Forgets the failures of `key`, after a successful login or an unlock.
+pub fn clear(connection: &mut PgConnection, key: &str) -> Result<(), diesel::result::Error> {
+    diesel::delete(login_throttles::table.filter(login_throttles::key.eq(key)))
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Deletes the keys that last failed before `cutoff`, which are forgotten
+/// once they do again, and returns how many were deleted.
+pub fn delete_stale(
+    connection: &mut PgConnection,
+    cutoff: NaiveDateTime,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(
+        login_throttles::table
+            .filter(login_throttles::last_failed_at.lt(cutoff))
+            .filter(
+                login_throttles::locked_until
+                    .is_null()
+                    .or(login_throttles::locked_until.lt(cutoff)),
+            ),
+    )
+    .execute(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::db::test_connection;
+
+    #[test]
+    fn failures_lock_the_key_until_cleared() {
+        let mut connection = test_connection();
+        let key = account_key(&format!("{}@example.com", Uuid::new_v4()));
+        let keys = [key.clone()];
+        let policy = LockoutPolicy {
+            threshold: 2,
+            base_secs: 60,
+            max_secs: 3600,
+        };
+
+        record_failure(&mut connection, key.clone(), policy).unwrap();
+        assert_eq!(locked_until(&mut connection, &keys).unwrap(), None);
+
+        let throttle = record_failure(&mut connection, key.clone(), policy).unwrap();
+        assert_eq!(throttle.failures, 2);
+        assert!(throttle.locked_until.is_some());
+        assert_eq!(locked_until(&mut connection, &keys).unwrap(), throttle.locked_until);
+
+        clear(&mut connection, &key).unwrap();
+        assert_eq!(locked_until(&mut connection, &keys).unwrap(), None);
+    }
+
+    #[test]
+    fn requests_count_until_any_key_is_locked() {
+        let mut connection = test_connection();
+        let email = format!("{}@example.com", Uuid::new_v4());
+        let strict = LockoutPolicy {
+            threshold: 2,
+            base_secs: 60,
+            max_secs: 3600,
+        };
+        let lenient = LockoutPolicy {
+            threshold: 10,
+            ..strict
+        };
+        let keys = || {
+            vec![
+                (unlock_key(&email), strict),
+                (unlock_ip_key(&Uuid::new_v4().to_string()), lenient),
+            ]
+        };
+
+        assert_eq!(count_request(&mut connection, keys()).unwrap(), None);
+        assert_eq!(count_request(&mut connection, keys()).unwrap(), None);
+        assert!(count_request(&mut connection, keys()).unwrap().is_some());
+        // Logins by the same email aren't locked by it
+        assert_eq!(locked_until(&mut connection, &[account_key(&email)]).unwrap(), None);
+    }
+
+    #[test]
+    fn stale_keys_are_deleted() {
+        let mut connection = test_connection();
+        let policy = LockoutPolicy {
+            threshold: 5,
+            base_secs: 60,
+            max_secs: 3600,
+        };
+        let stale = ip_key(&Uuid::new_v4().to_string());
+        let fresh = ip_key(&Uuid::new_v4().to_string());
+        record_failure(&mut connection, stale.clone(), policy).unwrap();
+        diesel::update(login_throttles::table.filter(login_throttles::key.eq(&stale)))
+            .set(login_throttles::last_failed_at.eq(Utc::now().naive_utc() - chrono::Duration::hours(2)))
+            .execute(&mut connection)
+            .unwrap();
+        record_failure(&mut connection, fresh.clone(), policy).unwrap();
+
+        let cutoff = Utc::now().naive_utc() - chrono::Duration::seconds(policy.max_secs);
+        assert!(delete_stale(&mut connection, cutoff).unwrap() >= 1);
+
+        let keys: Vec<String> = login_throttles::table
+            .select(login_throttles::key)
+            .filter(login_throttles::key.eq_any([&stale, &fresh]))
+            .load(&mut connection)
+            .unwrap();
+        assert_eq!(keys, vec![fresh]);
+    }
+
+    #[test]
+    fn locks_double_from_the_threshold_up_to_the_maximum() {
+        let policy = LockoutPolicy {
+            threshold: 3,
+            base_secs: 30,
+            max_secs: 3600,
+        };
+
+        assert_eq!(policy.lock_secs(1), None);
+        assert_eq!(policy.lock_secs(2), None);
+        assert_eq!(policy.lock_secs(3), Some(30));
+        assert_eq!(policy.lock_secs(4), Some(60));
+        assert_eq!(policy.lock_secs(5), Some(120));
+        assert_eq!(policy.lock_secs(10), Some(3600));
+        assert_eq!(policy.lock_secs(i32::MAX), Some(3600));
+    }
+
+    #[test]
+    fn account_keys_ignore_case_and_whitespace() {
+        assert_eq!(account_key(" Samuel@Example.com "), account_key("samuel@example.com"));
+        assert_ne!(account_key("samuel@example.com"), account_key("sam@example.com"));
+        assert!(account_key(&"a".repeat(400)).len() < 100);
+        assert_eq!(ip_key("127.0.0.1"), "ip:127.0.0.1");
+    }
+}
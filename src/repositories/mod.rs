@@ -2,3 +2,14 @@ pub mod user_repository;
 pub mod session_repository;
 pub mod postgres_store;
 pub mod auth_backend;
+pub mod todo_repository;
+pub mod feed_repository;
+pub mod password_reset_repository;
+pub mod email_verification_repository;
+pub mod login_throttle_repository;
+pub mod account_unlock_repository;
+pub mod two_factor_repository;
+pub mod role_repository;
+pub mod impersonation_repository;
+pub mod export_repository;
+pub mod audit_repository;
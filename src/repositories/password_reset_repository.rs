@@ -0,0 +1,150 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    Connection, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper,
+};
+use crate::db::schema::password_reset_tokens;
+use crate::tokens;
+use serde::Deserialize;
+use uuid::Uuid;
+use diesel::pg::PgConnection;
+
+use super::user_repository;
+
+/// How long a password reset link keeps working.
+pub const TOKEN_TTL_MINUTES: i64 = 60;
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = password_reset_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PasswordResetTokenDb {
+    pub id: Uuid,
+    pub user_id: Uuid,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = password_reset_tokens)]
+pub struct NewPasswordResetTokenDb {
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ForgotPasswordForm {
+    pub email: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ResetPasswordForm {
+    pub password: String,
+}
+
+/// Creates a reset token for the user and returns it. Only its hash is
+/// stored, and any older unused tokens of the user stop working.
+pub fn create_token(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<String, diesel::result::Error> {
+    let token = tokens::generate();
+
+    connection.transaction(|connection| {
+        diesel::delete(
+            password_reset_tokens::table
+                .filter(password_reset_tokens::user_id.eq(user_id))
+                .filter(password_reset_tokens::used_at.is_null()),
+        )
+        .execute(connection)?;
+
+        diesel::insert_into(password_reset_tokens::table)
+            .values(NewPasswordResetTokenDb {
+                user_id,
+                token_hash: tokens::hash(&token),
+                expires_at: Utc::now().naive_utc() + chrono::Duration::minutes(TOKEN_TTL_MINUTES),
+            })
+            .execute(connection)?;
+
+        Ok(token)
+    })
+}
+
+/// Returns `None` for unknown, expired and already used tokens alike.
+pub fn get_valid(
+    connection: &mut PgConnection,
+    token: &str,
+) -> Result<Option<PasswordResetTokenDb>, diesel::result::Error> {
+    let result = password_reset_tokens::table
+        .select(PasswordResetTokenDb::as_select())
+        .filter(password_reset_tokens::token_hash.eq(tokens::hash(token)))
+        .filter(password_reset_tokens::used_at.is_null())
+        .filter(password_reset_tokens::expires_at.gt(Utc::now().naive_utc()))
+        .first(connection)
+        .optional()?;
+
+    Ok(result)
+}
+
+/// Sets the user's new password and marks the token used, returning the user
+/// id, or `None` if the token isn't valid (anymore). The token row is locked
+/// so the same link can't be used twice concurrently.
+pub fn reset_password(
+    connection: &mut PgConnection,
+    token: &str,
+    password: String,
+) -> Result<Option<Uuid>, diesel::result::Error> {
+    connection.transaction(|connection| {
+        let token = password_reset_tokens::table
+            .select(PasswordResetTokenDb::as_select())
+            .filter(password_reset_tokens::token_hash.eq(tokens::hash(token)))
+            .filter(password_reset_tokens::used_at.is_null())
+            .filter(password_reset_tokens::expires_at.gt(Utc::now().naive_utc()))
+            .for_update()
+            .first(connection)
+            .optional()?;
+        let Some(token) = token else {
+            return Ok(None);
+        };
+
+        diesel::update(password_reset_tokens::table.filter(password_reset_tokens::id.eq(token.id)))
+            .set(password_reset_tokens::used_at.eq(diesel::dsl::now))
+            .execute(connection)?;
+        user_repository::update_password(connection, token.user_id, password)?;
+
+        Ok(Some(token.user_id))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use crate::repositories::user_repository::NewUserDb;
+
+    #[test]
+    fn tokens_reset_the_password_once() {
+        let mut connection = test_connection();
+        let user = user_repository::create_user(
+            &mut connection,
+            NewUserDb {
+                name: "Samuel".to_string(),
+                email: format!("{}@example.com", Uuid::new_v4()),
+                password: "old password".to_string(),
+            },
+        )
+        .unwrap();
+        let replaced = create_token(&mut connection, user.id).unwrap();
+        let token = create_token(&mut connection, user.id).unwrap();
+
+        assert!(get_valid(&mut connection, &replaced).unwrap().is_none());
+        assert_eq!(get_valid(&mut connection, &token).unwrap().unwrap().user_id, user.id);
+
+        let reset = reset_password(&mut connection, &token, "new password".to_string());
+        assert_eq!(reset.unwrap(), Some(user.id));
+        let user = user_repository::get_by_id(&mut connection, user.id).unwrap();
+        assert!(password_auth::verify_password("new password", &user.password).is_ok());
+
+        assert!(get_valid(&mut connection, &token).unwrap().is_none());
+        let reused = reset_password(&mut connection, &token, "other password".to_string());
+        assert_eq!(reused.unwrap(), None);
+    }
+}
@@ -1,11 +1,22 @@
 use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::{
+    pg::PgConnection,
+    r2d2::{ConnectionManager, PooledConnection},
+};
 use tower_sessions::{
     session::{Id, Record},
     session_store, ExpiredDeletion, SessionStore,
 };
+use uuid::Uuid;
+use crate::client_info::{ClientInfo, CLIENT_INFO_KEY};
 use crate::PgPool;
 
-use super::session_repository;
+use super::auth_backend::AUTH_DATA_KEY;
+use super::session_repository::{self, SessionDb};
+
+/// How many expired sessions `delete_expired` removes per statement.
+pub const DEFAULT_DELETE_BATCH_SIZE: i64 = 1000;
 
 /// A PostgreSQL session store.
 #[derive(Clone, Debug)]
@@ -29,25 +40,176 @@ impl PostgresStore {
     /// let session_store = PostgresStore::new(pool);
     /// # })
     /// ```
+    ///
+    /// By default the store uses the `public.sessions` table created by our
+    /// Diesel migrations.
     pub fn new(pool: PgPool) -> Self {
         Self {
             pool,
-            schema_name: "sessions".to_string(),
-            table_name: "session".to_string(),
+            schema_name: "public".to_string(),
+            table_name: "sessions".to_string(),
+        }
+    }
+
+    /// Set the session table schema name with the provided name.
+    pub fn with_schema_name(mut self, schema_name: impl AsRef<str>) -> Result<Self, String> {
+        let schema_name = schema_name.as_ref();
+        if !is_valid_identifier(schema_name) {
+            return Err(format!(
+                "Invalid schema name '{}'. Schema names must start with a letter or underscore \
+                 (including letters with diacritical marks and non-Latin letters). Subsequent \
+                 characters can be letters, underscores, digits (0-9), or dollar signs ($).",
+                schema_name
+            ));
+        }
+
+        self.schema_name = schema_name.to_string();
+        Ok(self)
+    }
+
+    /// Set the session table name with the provided name.
+    pub fn with_table_name(mut self, table_name: impl AsRef<str>) -> Result<Self, String> {
+        let table_name = table_name.as_ref();
+        if !is_valid_identifier(table_name) {
+            return Err(format!(
+                "Invalid table name '{}'. Table names must start with a letter or underscore \
+                 (including letters with diacritical marks and non-Latin letters). Subsequent \
+                 characters can be letters, underscores, digits (0-9), or dollar signs ($).",
+                table_name
+            ));
+        }
+
+        self.table_name = table_name.to_string();
+        Ok(self)
+    }
+
+    /// Migrate the session schema, creating the schema and table if they don't
+    /// exist yet.
+    pub async fn migrate(&self) -> session_store::Result<()> {
+        let mut connection = self.connection()?;
+
+        let schema = format!(r#""{}""#, self.schema_name);
+        let table = self.qualified_table_name();
+        let index_prefix = self.table_name.clone();
+        tokio::task::spawn_blocking(move || {
+            session_repository::create_table(&mut connection, &schema, &table, &index_prefix)
+        })
+        .await
+        .map_err(backend_error)?
+        .map_err(backend_error)?;
+
+        Ok(())
+    }
+
+    /// The quoted, schema qualified table name. Both parts are validated by
+    /// `is_valid_identifier`, so they can't contain quotes.
+    fn qualified_table_name(&self) -> String {
+        format!(r#""{}"."{}""#, self.schema_name, self.table_name)
+    }
+
+    fn connection(&self) -> session_store::Result<PooledConnection<ConnectionManager<PgConnection>>> {
+        self.pool.get().map_err(backend_error)
+    }
+
+    /// Deletes expired sessions in batches of `batch_size`, each in its own
+    /// statement, and returns the total number of deleted rows.
+    pub async fn delete_expired_in_batches(&self, batch_size: i64) -> session_store::Result<usize> {
+        let mut total = 0;
+
+        loop {
+            let mut connection = self.connection()?;
+            let table = self.qualified_table_name();
+            let deleted = tokio::task::spawn_blocking(move || {
+                session_repository::delete_expired(&mut connection, &table, batch_size)
+            })
+            .await
+            .map_err(backend_error)?
+            .map_err(backend_error)?;
+
+            total += deleted;
+            if (deleted as i64) < batch_size {
+                return Ok(total);
+            }
         }
     }
+
+    /// Whether the session is still in the table and unexpired, so that
+    /// writing it again wouldn't bring back a session that was revoked.
+    pub async fn exists(&self, session_id: Id) -> session_store::Result<bool> {
+        let mut connection = self.connection()?;
+
+        let table = self.qualified_table_name();
+        tokio::task::spawn_blocking(move || {
+            session_repository::get_by_id(&mut connection, &table, session_id.to_string())
+        })
+        .await
+        .map_err(backend_error)?
+        .map(|session| session.is_some())
+        .map_err(backend_error)
+    }
+
+    /// The user's unexpired sessions, most recently used first.
+    pub async fn get_by_user_id(&self, user_id: Uuid) -> session_store::Result<Vec<SessionDb>> {
+        let mut connection = self.connection()?;
+
+        let table = self.qualified_table_name();
+        tokio::task::spawn_blocking(move || {
+            session_repository::get_by_user_id(&mut connection, &table, user_id)
+        })
+        .await
+        .map_err(backend_error)?
+        .map_err(backend_error)
+    }
+
+    /// Deletes every session of the user except `keep`, logging them out
+    /// everywhere else, and returns how many were deleted.
+    pub async fn delete_by_user_id(
+        &self,
+        user_id: Uuid,
+        keep: Option<Id>,
+    ) -> session_store::Result<usize> {
+        let mut connection = self.connection()?;
+
+        let table = self.qualified_table_name();
+        tokio::task::spawn_blocking(move || {
+            session_repository::delete_by_user_id(
+                &mut connection,
+                &table,
+                user_id,
+                keep.map(|id| id.to_string()),
+            )
+        })
+        .await
+        .map_err(backend_error)?
+        .map_err(backend_error)
+    }
+}
+
+/// The client details `track_client` stored in `record`, if any.
+fn record_client_info(record: &Record) -> Option<ClientInfo> {
+    serde_json::from_value(record.data.get(CLIENT_INFO_KEY)?.clone()).ok()
+}
+
+/// The id of the user logged in to `record`, as stored by axum-login.
+fn record_user_id(record: &Record) -> Option<Uuid> {
+    record
+        .data
+        .get(AUTH_DATA_KEY)?
+        .get("user_id")?
+        .as_str()?
+        .parse()
+        .ok()
+}
+
+/// Pool, database and blocking task failures all surface as backend errors.
+fn backend_error(err: impl ToString) -> session_store::Error {
+    session_store::Error::Backend(err.to_string())
 }
 
 #[async_trait]
 impl ExpiredDeletion for PostgresStore {
     async fn delete_expired(&self) -> session_store::Result<()> {
-        let mut connection = self.pool.get().unwrap();
-
-        let results = tokio::task::spawn_blocking(move || {
-            session_repository::delete_expired(&mut connection)
-        })
-        .await
-        .unwrap();
+        self.delete_expired_in_batches(DEFAULT_DELETE_BATCH_SIZE).await?;
 
         Ok(())
     }
@@ -56,38 +218,68 @@ impl ExpiredDeletion for PostgresStore {
 #[async_trait]
 impl SessionStore for PostgresStore {
     async fn save(&self, record: &Record) -> session_store::Result<()> {
-        let mut connection = self.pool.get().unwrap();
+        let mut connection = self.connection()?;
 
-        let record_close = record.clone();
-        let results =
-            tokio::task::spawn_blocking(move || session_repository::save(&mut connection, &record_close))
-                .await
-                .unwrap();
+        let expiry_date = NaiveDateTime::from_timestamp_opt(record.expiry_date.unix_timestamp(), 0)
+            .ok_or_else(|| {
+                session_store::Error::Encode(format!("invalid expiry date {}", record.expiry_date))
+            })?;
+        let client = record_client_info(record);
+        let now = Utc::now();
+        let session = SessionDb {
+            id: record.id.to_string(),
+            data: rmp_serde::to_vec(record)
+                .map_err(|err| session_store::Error::Encode(err.to_string()))?,
+            expiry_date: DateTime::from_naive_utc_and_offset(expiry_date, Utc),
+            user_id: record_user_id(record),
+            created_at: now,
+            // Sessions are only saved when they change, which means they were used
+            last_seen_at: now,
+            ip_address: client.as_ref().and_then(|client| client.ip_address.clone()),
+            user_agent: client.and_then(|client| client.user_agent),
+        };
+
+        let table = self.qualified_table_name();
+        tokio::task::spawn_blocking(move || session_repository::save(&mut connection, &table, &session))
+            .await
+            .map_err(backend_error)?
+            .map_err(backend_error)?;
 
         Ok(())
     }
 
     async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
-        let mut connection = self.pool.get().unwrap();
+        let mut connection = self.connection()?;
 
-        let session_id = session_id.clone();
+        let session_id = *session_id;
+        let table = self.qualified_table_name();
         let session = tokio::task::spawn_blocking(move || {
-            session_repository::get_by_id(&mut connection, session_id.to_string())
+            session_repository::get_by_id(&mut connection, &table, session_id.to_string())
         })
         .await
-        .unwrap()
-        .unwrap();
+        .map_err(backend_error)?
+        .map_err(backend_error)?;
 
-        Ok(Some(rmp_serde::from_slice(&session.data).unwrap()))
+        match session {
+            Some(session) => Ok(Some(
+                rmp_serde::from_slice(&session.data)
+                    .map_err(|err| session_store::Error::Decode(err.to_string()))?,
+            )),
+            None => Ok(None),
+        }
     }
 
     async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
-        let mut connection = self.pool.get().unwrap();
+        let mut connection = self.connection()?;
 
-        let session_id = session_id.clone();
-        let result = tokio::task::spawn_blocking(move || {
-            session_repository::delete_by_id(&mut connection, session_id.to_string())
-        }).await;
+        let session_id = *session_id;
+        let table = self.qualified_table_name();
+        tokio::task::spawn_blocking(move || {
+            session_repository::delete_by_id(&mut connection, &table, session_id.to_string())
+        })
+        .await
+        .map_err(backend_error)?
+        .map_err(backend_error)?;
 
         Ok(())
     }
@@ -97,7 +289,7 @@ impl SessionStore for PostgresStore {
 /// (including letters with diacritical marks and non-Latin letters). Subsequent
 /// characters in an identifier or key word can be letters, underscores, digits
 /// (0-9), or dollar signs ($). See https://www.postgresql.org/docs/current/sql-syntax-lexical.html#SQL-SYNTAX-IDENTIFIERS for details.
-fn is_valid_identifier(name: &str) -> bool {
+pub(crate) fn is_valid_identifier(name: &str) -> bool {
     !name.is_empty()
         && name
             .chars()
@@ -108,3 +300,229 @@ fn is_valid_identifier(name: &str) -> bool {
             .chars()
             .all(|c| c.is_alphanumeric() || c == '_' || c == '$')
 }
+
+#[cfg(test)]
+mod tests {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::db::schema::sessions;
+    use crate::db::test_pool;
+    use crate::repositories::user_repository::{self, NewUserDb};
+
+    use super::*;
+
+    /// These tests run against the database in `DATABASE_URL` (see
+    /// docker-compose.yml) with the migrations applied.
+    fn store() -> PostgresStore {
+        PostgresStore::new(test_pool())
+    }
+
+    fn record(expiry_date: OffsetDateTime) -> Record {
+        Record {
+            id: Id::default(),
+            data: [("user".to_string(), serde_json::json!("samuel"))].into(),
+            // The store keeps whole seconds only
+            expiry_date: expiry_date.replace_nanosecond(0).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn load_unknown_id_returns_none() {
+        let store = store();
+
+        assert_eq!(store.load(&Id::default()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn load_returns_saved_record() {
+        let store = store();
+        let record = record(OffsetDateTime::now_utc() + Duration::hours(1));
+
+        store.save(&record).await.unwrap();
+
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record));
+    }
+
+    #[tokio::test]
+    async fn load_ignores_expired_record() {
+        let store = store();
+        let record = record(OffsetDateTime::now_utc() - Duration::minutes(1));
+
+        store.save(&record).await.unwrap();
+
+        assert_eq!(store.load(&record.id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn load_reports_undecodable_data() {
+        let store = store();
+        let id = Id::default();
+        let mut connection = store.connection().unwrap();
+        session_repository::save(
+            &mut connection,
+            &store.qualified_table_name(),
+            &SessionDb {
+                id: id.to_string(),
+                data: vec![0xc1],
+                expiry_date: Utc::now() + chrono::Duration::hours(1),
+                user_id: None,
+                created_at: Utc::now(),
+                last_seen_at: Utc::now(),
+                ip_address: None,
+                user_agent: None,
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(
+            store.load(&id).await,
+            Err(session_store::Error::Decode(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn delete_expired_in_batches_removes_only_expired_records() {
+        let store = store();
+        let expired: Vec<Record> = (0..3)
+            .map(|_| record(OffsetDateTime::now_utc() - Duration::minutes(1)))
+            .collect();
+        let active = record(OffsetDateTime::now_utc() + Duration::hours(1));
+        for record in expired.iter().chain([&active]) {
+            store.save(record).await.unwrap();
+        }
+
+        // Other tests may leave expired rows behind, so only a lower bound holds
+        assert!(store.delete_expired_in_batches(2).await.unwrap() >= 3);
+
+        let mut connection = store.connection().unwrap();
+        for record in &expired {
+            let row = sessions::table
+                .filter(sessions::id.eq(record.id.to_string()))
+                .count()
+                .get_result::<i64>(&mut connection)
+                .unwrap();
+            assert_eq!(row, 0);
+        }
+        assert_eq!(store.load(&active.id).await.unwrap(), Some(active));
+    }
+
+    #[test]
+    fn with_names_rejects_invalid_identifiers() {
+        let store = store();
+
+        assert!(store.clone().with_schema_name("").is_err());
+        assert!(store.clone().with_schema_name("1sessions").is_err());
+        assert!(store.clone().with_table_name(r#"sessions"; DROP TABLE users; --"#).is_err());
+        assert!(store.clone().with_schema_name("tower_sessions").is_ok());
+        assert!(store.with_table_name("session$2").is_ok());
+    }
+
+    #[tokio::test]
+    async fn migrate_creates_configured_table() {
+        let store = store()
+            .with_schema_name("informator_test")
+            .unwrap()
+            .with_table_name("session")
+            .unwrap();
+        let record = record(OffsetDateTime::now_utc() + Duration::hours(1));
+
+        // Running it twice must be harmless
+        store.migrate().await.unwrap();
+        store.migrate().await.unwrap();
+        store.save(&record).await.unwrap();
+
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+        // The default table doesn't see sessions of the other store
+        assert_eq!(self::store().load(&record.id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn delete_by_user_id_keeps_the_current_session() {
+        let store = store();
+        let mut connection = store.connection().unwrap();
+        let user = user_repository::create_user(
+            &mut connection,
+            NewUserDb {
+                name: "Samuel".to_string(),
+                email: format!("{}@example.com", Uuid::new_v4()),
+                password: "password".to_string(),
+            },
+        )
+        .unwrap();
+        let logged_in = |_| {
+            let mut record = record(OffsetDateTime::now_utc() + Duration::hours(1));
+            record.data.insert(
+                AUTH_DATA_KEY.to_string(),
+                serde_json::json!({ "user_id": user.id, "auth_hash": [] }),
+            );
+            record
+        };
+        let records: Vec<Record> = (0..3).map(logged_in).collect();
+        let anonymous = record(OffsetDateTime::now_utc() + Duration::hours(1));
+        for record in records.iter().chain([&anonymous]) {
+            store.save(record).await.unwrap();
+        }
+
+        let deleted = store
+            .delete_by_user_id(user.id, Some(records[0].id))
+            .await
+            .unwrap();
+
+        assert_eq!(deleted, 2);
+        assert!(store.load(&records[0].id).await.unwrap().is_some());
+        assert_eq!(store.load(&records[1].id).await.unwrap(), None);
+        assert!(store.load(&anonymous.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn get_by_user_id_lists_client_details() {
+        let store = store();
+        let mut connection = store.connection().unwrap();
+        let user = user_repository::create_user(
+            &mut connection,
+            NewUserDb {
+                name: "Samuel".to_string(),
+                email: format!("{}@example.com", Uuid::new_v4()),
+                password: "password".to_string(),
+            },
+        )
+        .unwrap();
+        let mut record = record(OffsetDateTime::now_utc() + Duration::hours(1));
+        record.data.insert(
+            AUTH_DATA_KEY.to_string(),
+            serde_json::json!({ "user_id": user.id, "auth_hash": [] }),
+        );
+        record.data.insert(
+            CLIENT_INFO_KEY.to_string(),
+            serde_json::to_value(ClientInfo {
+                ip_address: Some("127.0.0.1".to_string()),
+                user_agent: Some("curl/8.5.0".to_string()),
+                seen_at: 0,
+            })
+            .unwrap(),
+        );
+        store.save(&record).await.unwrap();
+
+        let sessions = store.get_by_user_id(user.id).await.unwrap();
+
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, record.id.to_string());
+        assert_eq!(sessions[0].ip_address.as_deref(), Some("127.0.0.1"));
+        assert_eq!(sessions[0].user_agent.as_deref(), Some("curl/8.5.0"));
+        assert_ne!(sessions[0].handle(), sessions[0].id);
+    }
+
+    #[tokio::test]
+    async fn delete_removes_record() {
+        let store = store();
+        let record = record(OffsetDateTime::now_utc() + Duration::hours(1));
+        store.save(&record).await.unwrap();
+        assert!(store.exists(record.id).await.unwrap());
+
+        store.delete(&record.id).await.unwrap();
+
+        assert_eq!(store.load(&record.id).await.unwrap(), None);
+        assert!(!store.exists(record.id).await.unwrap());
+    }
+}
@@ -0,0 +1,131 @@
+use diesel::{ExpressionMethods, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper};
+use crate::db::schema::{permissions, role_permissions, roles, user_roles};
+use uuid::Uuid;
+use diesel::pg::PgConnection;
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = roles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RoleDb {
+    pub id: Uuid,
+    pub name: String,
+}
+
+pub fn get_roles(connection: &mut PgConnection) -> Result<Vec<RoleDb>, diesel::result::Error> {
+    let result = roles::table
+        .select(RoleDb::as_select())
+        .order(roles::name.asc())
+        .get_results(connection)?;
+
+    Ok(result)
+}
+
+pub fn get_by_name(
+    connection: &mut PgConnection,
+    name: &str,
+) -> Result<RoleDb, diesel::result::Error> {
+    let result = roles::table
+        .select(RoleDb::as_select())
+        .filter(roles::name.eq(name))
+        .first(connection)?;
+
+    Ok(result)
+}
+
+pub fn get_by_user_id(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<RoleDb>, diesel::result::Error> {
+    let result = roles::table
+        .inner_join(user_roles::table)
+        .select(RoleDb::as_select())
+        .filter(user_roles::user_id.eq(user_id))
+        .order(roles::name.asc())
+        .get_results(connection)?;
+
+    Ok(result)
+}
+
+/// The names of every permission any of the user's roles grants.
+pub fn get_permissions_by_user_id(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<String>, diesel::result::Error> {
+    let role_ids = user_roles::table
+        .select(user_roles::role_id)
+        .filter(user_roles::user_id.eq(user_id));
+    let permission_ids = role_permissions::table
+        .select(role_permissions::permission_id)
+        .filter(role_permissions::role_id.eq_any(role_ids));
+
+    let result = permissions::table
+        .select(permissions::name)
+        .filter(permissions::id.eq_any(permission_ids))
+        .get_results(connection)?;
+
+    Ok(result)
+}
+
+/// Returns `false` when the user had the role already.
+pub fn assign(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    role_id: Uuid,
+) -> Result<bool, diesel::result::Error> {
+    let inserted = diesel::insert_into(user_roles::table)
+        .values((user_roles::user_id.eq(user_id), user_roles::role_id.eq(role_id)))
+        .on_conflict_do_nothing()
+        .execute(connection)?;
+
+    Ok(inserted == 1)
+}
+
+/// Returns `false` when the user didn't have the role.
+pub fn revoke(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    role_id: Uuid,
+) -> Result<bool, diesel::result::Error> {
+    let deleted = diesel::delete(
+        user_roles::table
+            .filter(user_roles::user_id.eq(user_id))
+            .filter(user_roles::role_id.eq(role_id)),
+    )
+    .execute(connection)?;
+
+    Ok(deleted == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use crate::models::permission::{AUDIT_VIEW, USERS_MANAGE, USERS_VIEW};
+    use crate::repositories::user_repository::{self, NewUserDb};
+
+    #[test]
+    fn roles_grant_their_permissions() {
+        let mut connection = test_connection();
+        let user = user_repository::create_user(
+            &mut connection,
+            NewUserDb {
+                name: "Samuel".to_string(),
+                email: format!("{}@example.com", Uuid::new_v4()),
+                password: "password".to_string(),
+            },
+        )
+        .unwrap();
+        let admin = get_by_name(&mut connection, "admin").unwrap();
+        assert!(get_permissions_by_user_id(&mut connection, user.id).unwrap().is_empty());
+
+        assert!(assign(&mut connection, user.id, admin.id).unwrap());
+        assert!(!assign(&mut connection, user.id, admin.id).unwrap());
+        let mut permissions = get_permissions_by_user_id(&mut connection, user.id).unwrap();
+        permissions.sort();
+        assert_eq!(permissions, [AUDIT_VIEW, USERS_MANAGE, USERS_VIEW]);
+        assert_eq!(get_by_user_id(&mut connection, user.id).unwrap()[0].name, "admin");
+
+        assert!(revoke(&mut connection, user.id, admin.id).unwrap());
+        assert!(get_permissions_by_user_id(&mut connection, user.id).unwrap().is_empty());
+    }
+}
@@ -1,66 +1,206 @@
 use crate::db::schema::sessions;
+use crate::models::session::SessionModel;
+use crate::tokens;
 use chrono::DateTime;
-use chrono::NaiveDateTime;
 use chrono::Utc;
 use diesel::pg::PgConnection;
-use diesel::upsert::excluded;
-use diesel::{
-    ExpressionMethods, Insertable, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper,
-};
-use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
-use tower_sessions::session::Record;
+use diesel::sql_types::{BigInt, Bytea, Nullable, Text, Timestamptz, Uuid as SqlUuid};
+use diesel::{sql_query, Connection, QueryableByName, RunQueryDsl};
+use serde::Serialize;
 use uuid::Uuid;
 
-#[derive(Serialize, Queryable, Selectable, Insertable)]
+// The session table is configurable on `PostgresStore`, so the queries below
+// can't use the static `sessions` table from the Diesel schema and are written
+// as SQL instead. `table` is always the quoted, schema qualified name built by
+// the store from validated identifiers, e.g. `"public"."sessions"`.
+
+#[derive(Serialize, QueryableByName)]
 #[diesel(table_name = sessions)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct SessionDb {
     pub id: String,
     pub data: Vec<u8>,
     pub expiry_date: DateTime<Utc>,
+    /// The logged in user, `None` for anonymous sessions.
+    pub user_id: Option<Uuid>,
+    /// Only written when the row is inserted.
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl SessionDb {
+    /// Identifies the session on the active sessions page. The id itself lets
+    /// whoever holds it act as the user, so it never leaves the cookie.
+    pub fn handle(&self) -> String {
+        tokens::hash(&self.id)
+    }
+
+    /// `current_id` is the session making the request, so the page can tell
+    /// it apart from the user's other devices.
+    pub fn to_model(&self, current_id: Option<&str>) -> SessionModel {
+        SessionModel {
+            handle: self.handle(),
+            created_at: self.created_at,
+            last_seen_at: self.last_seen_at,
+            ip_address: self.ip_address.clone(),
+            user_agent: self.user_agent.clone(),
+            current: current_id == Some(self.id.as_str()),
+        }
+    }
 }
 
-pub fn save(connection: &mut PgConnection, record: &Record) -> Result<(), diesel::result::Error> {
-    let naive_datetime =
-        NaiveDateTime::from_timestamp_opt(record.expiry_date.unix_timestamp(), 0).unwrap();
-    let datetime: DateTime<Utc> = DateTime::from_naive_utc_and_offset(naive_datetime, Utc);
-
-    let new_session = SessionDb {
-        id: record.id.to_string(),
-        data: rmp_serde::to_vec(&record).map_err(|_| diesel::result::Error::RollbackTransaction)?,
-        expiry_date: datetime,
-    };
-
-    diesel::insert_into(sessions::table)
-        .values(&new_session)
-        .on_conflict(sessions::id)
-        .do_update()
-        .set((
-            sessions::data.eq(excluded(sessions::data)),
-            sessions::expiry_date.eq(excluded(sessions::expiry_date)),
+const COLUMNS: &str = "id, data, expiry_date, user_id, created_at, last_seen_at, ip_address, user_agent";
+
+/// Creates the schema, the session table and its indexes if they don't exist
+/// yet. `index_prefix` is the unquoted table name the index names start with.
+pub fn create_table(
+    connection: &mut PgConnection,
+    schema: &str,
+    table: &str,
+    index_prefix: &str,
+) -> Result<(), diesel::result::Error> {
+    connection.transaction(|connection| {
+        sql_query(format!("CREATE SCHEMA IF NOT EXISTS {schema}")).execute(connection)?;
+        sql_query(format!(
+            "CREATE TABLE IF NOT EXISTS {table} (
+                id TEXT PRIMARY KEY NOT NULL,
+                data BYTEA NOT NULL,
+                expiry_date TIMESTAMPTZ NOT NULL
+            )"
+        ))
+        .execute(connection)?;
+        // Tables created before sessions knew their user and client
+        sql_query(format!(
+            "ALTER TABLE {table}
+                ADD COLUMN IF NOT EXISTS user_id UUID,
+                ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                ADD COLUMN IF NOT EXISTS ip_address TEXT,
+                ADD COLUMN IF NOT EXISTS user_agent TEXT"
+        ))
+        .execute(connection)?;
+        sql_query(format!(
+            r#"CREATE INDEX IF NOT EXISTS "{index_prefix}_expiry_date_idx" ON {table} (expiry_date)"#
+        ))
+        .execute(connection)?;
+        sql_query(format!(
+            r#"CREATE INDEX IF NOT EXISTS "{index_prefix}_user_id_idx" ON {table} (user_id)"#
         ))
         .execute(connection)?;
 
-    Ok(())
+        Ok(())
+    })
 }
 
-pub fn delete_expired(connection: &mut PgConnection) -> Result<(), diesel::result::Error> {
-    diesel::delete(sessions::table.filter(sessions::expiry_date.lt(Utc::now()))).execute(connection)?;
+pub fn save(
+    connection: &mut PgConnection,
+    table: &str,
+    session: &SessionDb,
+) -> Result<(), diesel::result::Error> {
+    sql_query(format!(
+        "INSERT INTO {table} ({COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+         ON CONFLICT (id) DO UPDATE
+         SET data = EXCLUDED.data,
+             expiry_date = EXCLUDED.expiry_date,
+             user_id = EXCLUDED.user_id,
+             last_seen_at = EXCLUDED.last_seen_at,
+             ip_address = EXCLUDED.ip_address,
+             user_agent = EXCLUDED.user_agent"
+    ))
+    .bind::<Text, _>(&session.id)
+    .bind::<Bytea, _>(&session.data)
+    .bind::<Timestamptz, _>(session.expiry_date)
+    .bind::<Nullable<SqlUuid>, _>(session.user_id)
+    .bind::<Timestamptz, _>(session.created_at)
+    .bind::<Timestamptz, _>(session.last_seen_at)
+    .bind::<Nullable<Text>, _>(&session.ip_address)
+    .bind::<Nullable<Text>, _>(&session.user_agent)
+    .execute(connection)?;
 
     Ok(())
 }
 
-pub fn get_by_id(connection: &mut PgConnection, id: String) -> Result<SessionDb, diesel::result::Error> {
-    let session = sessions::table
-        .filter(sessions::id.eq(id))
-        .first::<SessionDb>(connection)?;
+/// Deletes at most `batch_size` expired sessions and returns how many were
+/// deleted. Callers loop until a short batch so that a large backlog never
+/// holds locks on the whole table at once.
+pub fn delete_expired(
+    connection: &mut PgConnection,
+    table: &str,
+    batch_size: i64,
+) -> Result<usize, diesel::result::Error> {
+    let deleted = sql_query(format!(
+        "DELETE FROM {table} WHERE id IN (
+            SELECT id FROM {table} WHERE expiry_date < $1 LIMIT $2
+        )"
+    ))
+    .bind::<Timestamptz, _>(Utc::now())
+    .bind::<BigInt, _>(batch_size)
+    .execute(connection)?;
 
-    Ok(session)
+    Ok(deleted)
 }
 
-pub fn delete_by_id(connection: &mut PgConnection, id: String) -> Result<(), diesel::result::Error> {
-    diesel::delete(sessions::table.filter(sessions::id.eq(id))).execute(connection)?;
+/// Returns `None` both for unknown ids and for sessions past their expiry
+/// date, which may still be in the table until they are reaped.
+pub fn get_by_id(
+    connection: &mut PgConnection,
+    table: &str,
+    id: String,
+) -> Result<Option<SessionDb>, diesel::result::Error> {
+    let sessions = sql_query(format!(
+        "SELECT {COLUMNS} FROM {table} WHERE id = $1 AND expiry_date > $2"
+    ))
+    .bind::<Text, _>(id)
+    .bind::<Timestamptz, _>(Utc::now())
+    .get_results::<SessionDb>(connection)?;
+
+    Ok(sessions.into_iter().next())
+}
+
+pub fn delete_by_id(
+    connection: &mut PgConnection,
+    table: &str,
+    id: String,
+) -> Result<(), diesel::result::Error> {
+    sql_query(format!("DELETE FROM {table} WHERE id = $1"))
+        .bind::<Text, _>(id)
+        .execute(connection)?;
 
     Ok(())
 }
+
+/// Deletes every session of `user_id` except `keep_id`, the one making the
+/// request, and returns how many were deleted.
+pub fn delete_by_user_id(
+    connection: &mut PgConnection,
+    table: &str,
+    user_id: Uuid,
+    keep_id: Option<String>,
+) -> Result<usize, diesel::result::Error> {
+    let deleted = sql_query(format!(
+        "DELETE FROM {table} WHERE user_id = $1 AND id IS DISTINCT FROM $2"
+    ))
+    .bind::<SqlUuid, _>(user_id)
+    .bind::<Nullable<Text>, _>(keep_id)
+    .execute(connection)?;
+
+    Ok(deleted)
+}
+
+/// The unexpired sessions of `user_id`, most recently used first.
+pub fn get_by_user_id(
+    connection: &mut PgConnection,
+    table: &str,
+    user_id: Uuid,
+) -> Result<Vec<SessionDb>, diesel::result::Error> {
+    sql_query(format!(
+        "SELECT {COLUMNS} FROM {table}
+         WHERE user_id = $1 AND expiry_date > $2
+         ORDER BY last_seen_at DESC, id"
+    ))
+    .bind::<SqlUuid, _>(user_id)
+    .bind::<Timestamptz, _>(Utc::now())
+    .get_results(connection)
+}
@@ -0,0 +1,254 @@
+use diesel::{
+    Connection, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper,
+};
+use crate::db::schema::todos;
+use crate::models::todo::TodoModel;
+use crate::validation::ValidationErrors;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use diesel::pg::PgConnection;
+
+/// The size of the `description` column.
+const DESCRIPTION_MAX_LENGTH: usize = 255;
+
+#[derive(Serialize, Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = todos)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TodoDb {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub description: String,
+    pub done: bool,
+    pub position: i32,
+}
+
+impl TodoDb {
+    pub fn to_model(&self) -> TodoModel {
+        TodoModel {
+            id: self.id,
+            user_id: self.user_id,
+            description: self.description.clone(),
+            done: self.done,
+        }
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = todos)]
+pub struct NewTodoDb {
+    pub user_id: Uuid,
+    pub description: String,
+}
+
+// This allows us to extract the todo fields from the htmx form. The owner is
+// never taken from the form, it always comes from the auth session.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TodoForm {
+    pub description: String,
+}
+
+impl TodoForm {
+    /// The trimmed description.
+    pub fn validate(&self) -> Result<String, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let description = self.description.trim().to_string();
+        check_description(&description, &mut errors);
+
+        errors.into_result(description)
+    }
+}
+
+fn check_description(description: &str, errors: &mut ValidationErrors) {
+    if description.is_empty() {
+        errors.add("description", "Please describe the todo.");
+    } else if description.chars().count() > DESCRIPTION_MAX_LENGTH {
+        errors.add(
+            "description",
+            format!("Description can be at most {} characters.", DESCRIPTION_MAX_LENGTH),
+        );
+    }
+}
+
+pub fn get_by_user_id(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<TodoDb>, diesel::result::Error> {
+    let result = todos::table
+        .select(TodoDb::as_select())
+        .filter(todos::user_id.eq(user_id))
+        .order((todos::position.asc(), todos::created_at.asc()))
+        .get_results(connection)?;
+
+    Ok(result)
+}
+
+/// Every lookup below is scoped to the owner, so a todo belonging to another
+/// user behaves exactly like one that doesn't exist (`Ok(None)`).
+pub fn get_by_id(
+    connection: &mut PgConnection,
+    todo_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<TodoDb>, diesel::result::Error> {
+    let result = todos::table
+        .select(TodoDb::as_select())
+        .filter(todos::id.eq(todo_id))
+        .filter(todos::user_id.eq(user_id))
+        .first::<TodoDb>(connection)
+        .optional()?;
+
+    Ok(result)
+}
+
+pub fn create_todo(
+    connection: &mut PgConnection,
+    todo: NewTodoDb,
+) -> Result<TodoDb, diesel::result::Error> {
+    connection.transaction(|connection| {
+        // New todos go to the bottom of the owner's list
+        let last_position = todos::table
+            .filter(todos::user_id.eq(todo.user_id))
+            .select(diesel::dsl::max(todos::position))
+            .first::<Option<i32>>(connection)?;
+
+        let result = diesel::insert_into(todos::table)
+            .values((&todo, todos::position.eq(last_position.map_or(0, |p| p + 1))))
+            .returning(TodoDb::as_returning())
+            .get_result(connection)?;
+
+        Ok(result)
+    })
+}
+
+pub fn update_description(
+    connection: &mut PgConnection,
+    todo_id: Uuid,
+    user_id: Uuid,
+    description: String,
+) -> Result<Option<TodoDb>, diesel::result::Error> {
+    let result = diesel::update(
+        todos::table
+            .filter(todos::id.eq(todo_id))
+            .filter(todos::user_id.eq(user_id)),
+    )
+    .set(todos::description.eq(description))
+    .returning(TodoDb::as_returning())
+    .get_result(connection)
+    .optional()?;
+
+    Ok(result)
+}
+
+pub fn toggle_done(
+    connection: &mut PgConnection,
+    todo_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<TodoDb>, diesel::result::Error> {
+    let result = diesel::update(
+        todos::table
+            .filter(todos::id.eq(todo_id))
+            .filter(todos::user_id.eq(user_id)),
+    )
+    .set(todos::done.eq(diesel::dsl::not(todos::done)))
+    .returning(TodoDb::as_returning())
+    .get_result(connection)
+    .optional()?;
+
+    Ok(result)
+}
+
+/// Stamps the first time the todo was completed and returns whether this was
+/// it, later completions after un-checking it return `false`.
+pub fn record_completion(
+    connection: &mut PgConnection,
+    todo_id: Uuid,
+) -> Result<bool, diesel::result::Error> {
+    let updated = diesel::update(
+        todos::table
+            .filter(todos::id.eq(todo_id))
+            .filter(todos::completed_at.is_null()),
+    )
+    .set(todos::completed_at.eq(diesel::dsl::now))
+    .execute(connection)?;
+
+    Ok(updated == 1)
+}
+
+/// Returns `false` when there was no such todo for the owner.
+pub fn delete_by_id(
+    connection: &mut PgConnection,
+    todo_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, diesel::result::Error> {
+    let deleted = diesel::delete(
+        todos::table
+            .filter(todos::id.eq(todo_id))
+            .filter(todos::user_id.eq(user_id)),
+    )
+    .execute(connection)?;
+
+    Ok(deleted > 0)
+}
+
+/// Persists the order of `todo_ids` as their positions. Ids that don't belong
+/// to the owner are ignored.
+pub fn reorder(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    todo_ids: Vec<Uuid>,
+) -> Result<(), diesel::result::Error> {
+    connection.transaction(|connection| {
+        for (position, todo_id) in todo_ids.into_iter().enumerate() {
+            diesel::update(
+                todos::table
+                    .filter(todos::id.eq(todo_id))
+                    .filter(todos::user_id.eq(user_id)),
+            )
+            .set(todos::position.eq(position as i32))
+            .execute(connection)?;
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use crate::repositories::user_repository::{self, NewUserDb};
+
+    #[test]
+    fn only_the_first_completion_is_recorded() {
+        let mut connection = test_connection();
+        let user = NewUserDb {
+            name: "Samuel".to_string(),
+            email: format!("{}@example.com", Uuid::new_v4()),
+            password: "password".to_string(),
+        };
+        let user = user_repository::create_user(&mut connection, user).unwrap();
+        let todo = NewTodoDb {
+            user_id: user.id,
+            description: "Buy milk".to_string(),
+        };
+        let todo = create_todo(&mut connection, todo).unwrap();
+
+        assert!(record_completion(&mut connection, todo.id).unwrap());
+        toggle_done(&mut connection, todo.id, user.id).unwrap();
+        toggle_done(&mut connection, todo.id, user.id).unwrap();
+        assert!(!record_completion(&mut connection, todo.id).unwrap());
+    }
+
+    #[test]
+    fn descriptions_are_trimmed_and_bounded() {
+        let form = |description: &str| TodoForm {
+            description: description.to_string(),
+        };
+
+        assert_eq!(form("  Buy milk ").validate(), Ok("Buy milk".to_string()));
+        assert!(form("").validate().is_err());
+        assert!(form(" \t ").validate().is_err());
+        assert!(form(&"é".repeat(DESCRIPTION_MAX_LENGTH)).validate().is_ok());
+        assert!(form(&"a".repeat(DESCRIPTION_MAX_LENGTH + 1)).validate().is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use chrono::NaiveDateTime;
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, Insertable, QueryDsl, Queryable,
+    RunQueryDsl, Selectable, SelectableHelper,
+};
+use crate::db::schema::{recovery_codes, users};
+use crate::tokens;
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use uuid::Uuid;
+use diesel::pg::PgConnection;
+
+/// Recovery codes handed out at once. Each one works a single time.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// Without look-alike characters, 32 of them so every one is equally likely.
+const RECOVERY_CODE_ALPHABET: &[u8; 32] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// The TOTP columns of a user. The secret is encrypted, see `totp::SecretCipher`.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = users)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TotpDb {
+    pub totp_secret: Option<Vec<u8>>,
+    pub totp_enabled_at: Option<NaiveDateTime>,
+    pub totp_last_step: Option<i64>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = recovery_codes)]
+pub struct NewRecoveryCodeDb {
+    pub user_id: Uuid,
+    pub code_hash: String,
+}
+
+/// A code from the authenticator app or a recovery code, whichever the user
+/// has at hand.
+#[derive(Clone, Deserialize)]
+pub struct TwoFactorCodeForm {
+    pub code: String,
+}
+
+pub fn get_totp(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<TotpDb, diesel::result::Error> {
+    let result = users::table
+        .select(TotpDb::as_select())
+        .filter(users::id.eq(user_id))
+        .first::<TotpDb>(connection)?;
+
+    Ok(result)
+}
+
+/// Stores a new, not yet enabled secret. Returns `false` when two-factor
+/// authentication is enabled already, its secret stays as it is then.
+pub fn start_enrolment(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    encrypted_secret: Vec<u8>,
+) -> Result<bool, diesel::result::Error> {
+    let updated = diesel::update(
+        users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::totp_enabled_at.is_null()),
+    )
+    .set((
+        users::totp_secret.eq(encrypted_secret),
+        users::totp_last_step.eq(None::<i64>),
+    ))
+    .execute(connection)?;
+
+    Ok(updated == 1)
+}
+
+/// Enables the pending secret once the user has confirmed a code of `step`
+/// and returns fresh recovery codes, or `None` when there is nothing to enable.
+pub fn enable(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    step: i64,
+) -> Result<Option<Vec<String>>, diesel::result::Error> {
+    connection.transaction(|connection| {
+        let updated = diesel::update(
+            users::table
+                .filter(users::id.eq(user_id))
+                .filter(users::totp_secret.is_not_null())
+                .filter(users::totp_enabled_at.is_null()),
+        )
+        .set((
+            users::totp_enabled_at.eq(diesel::dsl::now),
+            users::totp_last_step.eq(step),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)?;
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        replace_recovery_codes(connection, user_id).map(Some)
+    })
+}
+
+/// Forgets the secret and the recovery codes, logins only ask for the
+/// password again.
+pub fn disable(connection: &mut PgConnection, user_id: Uuid) -> Result<(), diesel::result::Error> {
+    connection.transaction(|connection| {
+        diesel::update(users::table.filter(users::id.eq(user_id)))
+            .set((
+                users::totp_secret.eq(None::<Vec<u8>>),
+                users::totp_enabled_at.eq(None::<NaiveDateTime>),
+                users::totp_last_step.eq(None::<i64>),
+                users::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(connection)?;
+
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+            .execute(connection)?;
+
+        Ok(())
+    })
+}
+
+/// Marks `step` as used. Returns `false` when it, or a later step, was used
+/// already, so of two concurrent logins with the same code only one succeeds.
+pub fn use_step(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    step: i64,
+) -> Result<bool, diesel::result::Error> {
+    let updated = diesel::update(
+        users::table
+            .filter(users::id.eq(user_id))
+            .filter(
+                users::totp_last_step
+                    .is_null()
+                    .or(users::totp_last_step.lt(step)),
+            ),
+    )
+    .set(users::totp_last_step.eq(step))
+    .execute(connection)?;
+
+    Ok(updated == 1)
+}
+
+/// Replaces the user's recovery codes with new ones and returns them. Only
+/// their hashes are stored, so this is the only time they can be shown.
+pub fn replace_recovery_codes(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<String>, diesel::result::Error> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+
+    connection.transaction(|connection| {
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+            .execute(connection)?;
+
+        let new_codes: Vec<NewRecoveryCodeDb> = codes
+            .iter()
+            .map(|code| NewRecoveryCodeDb {
+                user_id,
+                code_hash: tokens::hash(&normalize_recovery_code(code)),
+            })
+            .collect();
+        diesel::insert_into(recovery_codes::table)
+            .values(&new_codes)
+            .execute(connection)?;
+
+        Ok(codes)
+    })
+}
+
+/// Uses up one of the user's recovery codes. Returns `false` for unknown and
+/// already used codes.
+pub fn use_recovery_code(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, diesel::result::Error> {
+    let updated = diesel::update(
+        recovery_codes::table
+            .filter(recovery_codes::user_id.eq(user_id))
+            .filter(recovery_codes::code_hash.eq(tokens::hash(&normalize_recovery_code(code))))
+            .filter(recovery_codes::used_at.is_null()),
+    )
+    .set(recovery_codes::used_at.eq(diesel::dsl::now))
+    .execute(connection)?;
+
+    Ok(updated == 1)
+}
+
+pub fn count_unused_recovery_codes(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<i64, diesel::result::Error> {
+    let result = recovery_codes::table
+        .filter(recovery_codes::user_id.eq(user_id))
+        .filter(recovery_codes::used_at.is_null())
+        .count()
+        .get_result(connection)?;
+
+    Ok(result)
+}
+
+/// Ten random characters, shown as `xxxxx-xxxxx`.
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    OsRng.fill_bytes(&mut bytes);
+    let chars: String = bytes
+        .iter()
+        .map(|byte| RECOVERY_CODE_ALPHABET[(byte % 32) as usize] as char)
+        .collect();
+
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+/// Codes are accepted in any case and with or without the dash.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use crate::repositories::user_repository::{self, NewUserDb};
+
+    #[test]
+    fn enabling_hands_out_single_use_recovery_codes() {
+        let mut connection = test_connection();
+        let user = user_repository::create_user(
+            &mut connection,
+            NewUserDb {
+                name: "Samuel".to_string(),
+                email: format!("{}@example.com", Uuid::new_v4()),
+                password: "password".to_string(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(enable(&mut connection, user.id, 1).unwrap(), None);
+        assert!(start_enrolment(&mut connection, user.id, vec![1, 2, 3]).unwrap());
+        let codes = enable(&mut connection, user.id, 1).unwrap().unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(!start_enrolment(&mut connection, user.id, vec![4, 5, 6]).unwrap());
+        assert!(get_totp(&mut connection, user.id).unwrap().totp_enabled_at.is_some());
+
+        assert!(!use_step(&mut connection, user.id, 1).unwrap());
+        assert!(use_step(&mut connection, user.id, 2).unwrap());
+
+        let code = codes[0].to_uppercase().replace('-', "");
+        assert!(use_recovery_code(&mut connection, user.id, &code).unwrap());
+        assert!(!use_recovery_code(&mut connection, user.id, &codes[0]).unwrap());
+        assert_eq!(
+            count_unused_recovery_codes(&mut connection, user.id).unwrap(),
+            RECOVERY_CODE_COUNT as i64 - 1
+        );
+
+        disable(&mut connection, user.id).unwrap();
+        let totp = get_totp(&mut connection, user.id).unwrap();
+        assert!(totp.totp_secret.is_none() && totp.totp_enabled_at.is_none());
+        assert!(!use_recovery_code(&mut connection, user.id, &codes[1]).unwrap());
+    }
+}
@@ -1,24 +1,54 @@
 use axum_login::AuthUser;
 use diesel::{
-    ExpressionMethods, Insertable, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper,
+    BoolExpressionMethods, Connection, ExpressionMethods, Insertable, OptionalExtension,
+    PgTextExpressionMethods, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper,
 };
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
 use password_auth::generate_hash;
 // use diesel::sql_types::Uuid;
-use crate::db::schema::users;
+use crate::db::schema::{roles, user_roles, users};
+use crate::repositories::audit_repository;
+use crate::models::page::Page;
 use crate::models::user::UserModel;
+use crate::validation::{empty_as_none, is_valid_email, ValidationErrors};
+use data_encoding::BASE64URL_NOPAD;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 // use crate::infra::errors::{adapt_infra_error, InfraError};
-use diesel::pg::PgConnection;
+use diesel::pg::{Pg, PgConnection};
 
-#[derive(Serialize, Queryable, Selectable, Clone, Debug)]
+/// Passwords are hashed, so the upper bound only keeps hashing cheap.
+const PASSWORD_MIN_LENGTH: usize = 8;
+const PASSWORD_MAX_LENGTH: usize = 128;
+const NAME_MAX_LENGTH: usize = 255;
+const EMAIL_MAX_LENGTH: usize = 255;
+
+diesel::sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
+/// Shown in place of passwords and their hashes by the `Debug` impls below,
+/// so logging a user or a form never leaks them.
+const REDACTED: &str = "[redacted]";
+
+#[derive(Serialize, Queryable, Selectable, Clone)]
 #[diesel(table_name = users)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct UserDb {
     pub id: Uuid,
     pub name: String,
     pub email: String,
+    #[serde(skip_serializing)]
     pub password: String,
+    /// `None` until the user follows the link in the verification email.
+    pub email_verified_at: Option<NaiveDateTime>,
+    /// Set once the user has confirmed a code from their authenticator app,
+    /// from then on logins ask for one.
+    pub totp_enabled_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    /// Disabled users can't log in, and are logged out of existing sessions.
+    pub disabled_at: Option<NaiveDateTime>,
+    /// Deleted users are purged after a grace period, logging in before
+    /// then restores them.
+    pub deleted_at: Option<NaiveDateTime>,
 }
 
 impl UserDb {
@@ -27,10 +57,31 @@ impl UserDb {
             id: self.id,
             name: self.name.clone(),
             email: self.email.clone(),
+            created_at: self.created_at,
+            email_verified: self.email_verified_at.is_some(),
+            two_factor_enabled: self.totp_enabled_at.is_some(),
+            disabled: self.disabled_at.is_some(),
+            deleted: self.deleted_at.is_some(),
         }
     }
 }
 
+impl std::fmt::Debug for UserDb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserDb")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("email", &self.email)
+            .field("password", &REDACTED)
+            .field("email_verified_at", &self.email_verified_at)
+            .field("totp_enabled_at", &self.totp_enabled_at)
+            .field("created_at", &self.created_at)
+            .field("disabled_at", &self.disabled_at)
+            .field("deleted_at", &self.deleted_at)
+            .finish()
+    }
+}
+
 impl AuthUser for UserDb {
     type Id = Uuid;
 
@@ -47,7 +98,7 @@ impl AuthUser for UserDb {
 }
 
 
-#[derive(Deserialize, Insertable, Debug)]
+#[derive(Deserialize, Insertable)]
 #[diesel(table_name = users)]
 pub struct NewUserDb {
     pub name: String,
@@ -55,82 +106,689 @@ pub struct NewUserDb {
     pub password: String,
 }
 
+impl std::fmt::Debug for NewUserDb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NewUserDb")
+            .field("name", &self.name)
+            .field("email", &self.email)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
+/// The submitted registration form, turned into a `NewUserDb` by `validate`.
+#[derive(Clone, Default, Deserialize)]
+pub struct RegisterForm {
+    pub name: String,
+    pub email: String,
+    pub password: String,
+}
+
+impl RegisterForm {
+    /// Checks every field and trims the name and email.
+    pub fn validate(self) -> Result<NewUserDb, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let name = self.name.trim().to_string();
+        let email = self.email.trim().to_string();
+
+        check_name(&name, &mut errors);
+        check_email(&email, &mut errors);
+        check_password(&self.password, &mut errors);
+
+        errors.into_result(NewUserDb {
+            name,
+            email,
+            password: self.password,
+        })
+    }
+}
+
+/// The profile form on the account page.
+#[derive(Clone, Default, Deserialize)]
+pub struct ProfileForm {
+    pub name: String,
+}
+
+impl ProfileForm {
+    /// The trimmed name.
+    pub fn validate(self) -> Result<String, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let name = self.name.trim().to_string();
+        check_name(&name, &mut errors);
+
+        errors.into_result(name)
+    }
+}
+
+/// Changing the email needs the current password, it's how the account is
+/// recovered.
+#[derive(Clone, Default, Deserialize)]
+pub struct ChangeEmailForm {
+    pub email: String,
+    pub current_password: String,
+}
+
+impl ChangeEmailForm {
+    /// The trimmed email.
+    pub fn validate(&self) -> Result<String, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let email = self.email.trim().to_string();
+        check_email(&email, &mut errors);
+
+        errors.into_result(email)
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct ChangePasswordForm {
+    pub current_password: String,
+    pub password: String,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct DeleteAccountForm {
+    pub current_password: String,
+}
+
+fn check_name(name: &str, errors: &mut ValidationErrors) {
+    if name.is_empty() {
+        errors.add("name", "Please tell us your name.");
+    } else if name.chars().count() > NAME_MAX_LENGTH {
+        errors.add("name", format!("Your name can be at most {} characters.", NAME_MAX_LENGTH));
+    }
+}
+
+fn check_email(email: &str, errors: &mut ValidationErrors) {
+    if email.chars().count() > EMAIL_MAX_LENGTH {
+        errors.add("email", format!("Email can be at most {} characters.", EMAIL_MAX_LENGTH));
+    } else if !is_valid_email(email) {
+        errors.add("email", "That doesn't look like an email address.");
+    }
+}
+
+/// The password rules, shared by registration and password changes.
+pub fn check_password(password: &str, errors: &mut ValidationErrors) {
+    let length = password.chars().count();
+    if length < PASSWORD_MIN_LENGTH {
+        errors.add(
+            "password",
+            format!("Password must be at least {} characters.", PASSWORD_MIN_LENGTH),
+        );
+    } else if length > PASSWORD_MAX_LENGTH {
+        errors.add(
+            "password",
+            format!("Password can be at most {} characters.", PASSWORD_MAX_LENGTH),
+        );
+    }
+}
+
 // This allows us to extract the authentication fields from forms. We use this
 // to authenticate requests with the backend.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct Credentials {
     pub email: String,
     pub password: String,
     pub next: Option<String>,
+    /// Set by the login handler from the connection, failures are throttled
+    /// per address too.
+    #[serde(skip)]
+    pub ip_address: Option<String>,
+    /// Set by the login handler for the audit log.
+    #[serde(skip)]
+    pub user_agent: Option<String>,
 }
 
-pub fn get_users(conn: &mut PgConnection) -> Result<Vec<UserDb>, diesel::result::Error> {
-    let result = users::table.select(UserDb::as_select()).get_results(conn)?;
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("email", &self.email)
+            .field("password", &REDACTED)
+            .field("next", &self.next)
+            .field("ip_address", &self.ip_address)
+            .field("user_agent", &self.user_agent)
+            .finish()
+    }
+}
 
-    let users: Vec<UserDb> = result
-        .into_iter()
-        .map(|user_db| UserDb {
-            id: user_db.id,
-            name: user_db.name,
-            email: user_db.email,
-            password: user_db.password,
-        })
-        .collect();
+/// How user lists are sorted, `-` meaning descending.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum UserSort {
+    #[serde(rename = "name")]
+    NameAsc,
+    #[serde(rename = "-name")]
+    NameDesc,
+    #[serde(rename = "email")]
+    EmailAsc,
+    #[serde(rename = "-email")]
+    EmailDesc,
+    #[serde(rename = "created")]
+    CreatedAsc,
+    #[default]
+    #[serde(rename = "-created")]
+    CreatedDesc,
+}
+
+impl UserSort {
+    /// The query string value, as serde spells it.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserSort::NameAsc => "name",
+            UserSort::NameDesc => "-name",
+            UserSort::EmailAsc => "email",
+            UserSort::EmailDesc => "-email",
+            UserSort::CreatedAsc => "created",
+            UserSort::CreatedDesc => "-created",
+        }
+    }
+}
+
+/// Which users to list and how, as in the admin users table's query string.
+/// Empty fields don't filter.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserSearch {
+    /// Matched against names and emails, ignoring case.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub q: String,
+    /// The name of a role the users have.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub role: String,
+    /// Registered on or after this day.
+    #[serde(deserialize_with = "empty_as_none", skip_serializing_if = "Option::is_none")]
+    pub created_from: Option<NaiveDate>,
+    /// Registered on or before this day.
+    #[serde(deserialize_with = "empty_as_none", skip_serializing_if = "Option::is_none")]
+    pub created_to: Option<NaiveDate>,
+    pub sort: UserSort,
+    /// Offset pagination, starts at 1. Ignored when `after` is set.
+    pub page: i64,
+    /// Keyset pagination, the `next_cursor` of the page before.
+    #[serde(deserialize_with = "empty_as_none", skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+}
+
+/// Where a keyset page starts: right after the user with this sort key and id.
+#[derive(Serialize, Deserialize)]
+struct UserCursor {
+    key: UserCursorKey,
+    id: Uuid,
+}
+
+#[derive(Serialize, Deserialize)]
+enum UserCursorKey {
+    Name(String),
+    Email(String),
+    Created(NaiveDateTime),
+}
+
+impl UserCursor {
+    fn new(user: &UserDb, sort: UserSort) -> Self {
+        let key = match sort {
+            UserSort::NameAsc | UserSort::NameDesc => UserCursorKey::Name(user.name.clone()),
+            UserSort::EmailAsc | UserSort::EmailDesc => UserCursorKey::Email(user.email.clone()),
+            UserSort::CreatedAsc | UserSort::CreatedDesc => UserCursorKey::Created(user.created_at),
+        };
+
+        Self { key, id: user.id }
+    }
+
+    fn encode(&self) -> String {
+        BASE64URL_NOPAD.encode(&serde_json::to_vec(self).expect("cursors serialize"))
+    }
+
+    /// `None` for cursors that were tampered with.
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = BASE64URL_NOPAD.decode(cursor.as_bytes()).ok()?;
+
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+fn filtered_users(search: &UserSearch) -> users::BoxedQuery<'static, Pg> {
+    let mut query = users::table.into_boxed();
+
+    let q = search.q.trim();
+    if !q.is_empty() {
+        let pattern = format!("%{}%", escape_like(q));
+        query = query.filter(users::name.ilike(pattern.clone()).or(users::email.ilike(pattern)));
+    }
+
+    let role = search.role.trim();
+    if !role.is_empty() {
+        let user_ids = user_roles::table
+            .inner_join(roles::table)
+            .select(user_roles::user_id)
+            .filter(roles::name.eq(role.to_string()));
+        query = query.filter(users::id.eq_any(user_ids));
+    }
+
+    if let Some(from) = search.created_from {
+        query = query.filter(users::created_at.ge(from.and_time(NaiveTime::MIN)));
+    }
+    if let Some(to) = search.created_to.and_then(|to| to.succ_opt()) {
+        query = query.filter(users::created_at.lt(to.and_time(NaiveTime::MIN)));
+    }
+
+    query
+}
 
-    Ok(users)
+/// `%` and `_` in the search are taken literally.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
 }
 
+/// One page of the users matching the search. A cursor from another sort order
+/// or one that doesn't decode starts over at the first page.
+pub fn get_users(
+    connection: &mut PgConnection,
+    search: &UserSearch,
+    per_page: i64,
+) -> Result<Page<UserModel>, diesel::result::Error> {
+    let total = filtered_users(search).count().get_result(connection)?;
+
+    let mut query = filtered_users(search).select(UserDb::as_select());
+    // The id keeps the order stable when the sort key ties
+    query = match search.sort {
+        UserSort::NameAsc => query.order((users::name.asc(), users::id.asc())),
+        UserSort::NameDesc => query.order((users::name.desc(), users::id.desc())),
+        UserSort::EmailAsc => query.order((users::email.asc(), users::id.asc())),
+        UserSort::EmailDesc => query.order((users::email.desc(), users::id.desc())),
+        UserSort::CreatedAsc => query.order((users::created_at.asc(), users::id.asc())),
+        UserSort::CreatedDesc => query.order((users::created_at.desc(), users::id.desc())),
+    };
+
+    let cursor = search.after.as_deref().and_then(UserCursor::decode);
+    let number = match cursor {
+        Some(UserCursor { key, id }) => {
+            query = match (search.sort, key) {
+                (UserSort::NameAsc, UserCursorKey::Name(name)) => query.filter(
+                    users::name.gt(name.clone()).or(users::name.eq(name).and(users::id.gt(id))),
+                ),
+                (UserSort::NameDesc, UserCursorKey::Name(name)) => query.filter(
+                    users::name.lt(name.clone()).or(users::name.eq(name).and(users::id.lt(id))),
+                ),
+                (UserSort::EmailAsc, UserCursorKey::Email(email)) => query.filter(
+                    users::email.gt(email.clone()).or(users::email.eq(email).and(users::id.gt(id))),
+                ),
+                (UserSort::EmailDesc, UserCursorKey::Email(email)) => query.filter(
+                    users::email.lt(email.clone()).or(users::email.eq(email).and(users::id.lt(id))),
+                ),
+                (UserSort::CreatedAsc, UserCursorKey::Created(at)) => query.filter(
+                    users::created_at.gt(at).or(users::created_at.eq(at).and(users::id.gt(id))),
+                ),
+                (UserSort::CreatedDesc, UserCursorKey::Created(at)) => query.filter(
+                    users::created_at.lt(at).or(users::created_at.eq(at).and(users::id.lt(id))),
+                ),
+                _ => query,
+            };
+            None
+        }
+        None => {
+            let number = search.page.max(1);
+            query = query.offset((number - 1).saturating_mul(per_page));
+            Some(number)
+        }
+    };
+
+    // One more than fits tells whether there is a next page
+    let mut users: Vec<UserDb> = query.limit(per_page + 1).get_results(connection)?;
+    let has_next = users.len() as i64 > per_page;
+    users.truncate(per_page.max(0) as usize);
+    let next_cursor = users
+        .last()
+        .filter(|_| has_next)
+        .map(|user| UserCursor::new(user, search.sort).encode());
+
+    Ok(Page {
+        items: users.iter().map(|user| user.to_model()).collect(),
+        total,
+        per_page,
+        number,
+        next_cursor,
+    })
+}
+
+/// Disabling logs the user out of new requests, the caller deletes their
+/// sessions too.
+pub fn set_disabled(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    disabled: bool,
+) -> Result<(), diesel::result::Error> {
+    let disabled_at = disabled.then(|| chrono::Utc::now().naive_utc());
+
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::disabled_at.eq(disabled_at),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Starts the grace period, the caller deletes the user's sessions.
+pub fn mark_deleted(connection: &mut PgConnection, user_id: Uuid) -> Result<(), diesel::result::Error> {
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::deleted_at.eq(diesel::dsl::now),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Cancels a deletion during the grace period.
+pub fn restore(connection: &mut PgConnection, user_id: Uuid) -> Result<(), diesel::result::Error> {
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::deleted_at.eq(None::<NaiveDateTime>),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Up to `limit` users deleted before `cutoff`, the ones due to be purged.
+pub fn get_ids_deleted_before(
+    connection: &mut PgConnection,
+    cutoff: NaiveDateTime,
+    limit: i64,
+) -> Result<Vec<Uuid>, diesel::result::Error> {
+    let result = users::table
+        .select(users::id)
+        .filter(users::deleted_at.lt(cutoff))
+        .order(users::deleted_at.asc())
+        .limit(limit)
+        .get_results(connection)?;
+
+    Ok(result)
+}
+
+/// Deletes the user for good, and with the cascades everything they own.
+/// Their audit events are kept but scrubbed of anything about them. Returns
+/// `false` when they were restored in the meantime.
+pub fn purge(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    cutoff: NaiveDateTime,
+) -> Result<bool, diesel::result::Error> {
+    connection.transaction(|connection| {
+        let email = users::table
+            .select(users::email)
+            .filter(users::id.eq(user_id))
+            .filter(users::deleted_at.lt(cutoff))
+            .for_update()
+            .first::<String>(connection)
+            .optional()?;
+        let Some(email) = email else {
+            return Ok(false);
+        };
+
+        audit_repository::scrub_user(connection, user_id, &email)?;
+        diesel::delete(users::table.filter(users::id.eq(user_id))).execute(connection)?;
+
+        Ok(true)
+    })
+}
+
+/// Fails with a `UniqueViolation` when the email is already registered, in
+/// any case.
 pub fn create_user(
     connection: &mut PgConnection,
     user: NewUserDb,
 ) -> Result<UserDb, diesel::result::Error> {
-    let result = diesel::insert_into(users::table)
+    diesel::insert_into(users::table)
         .values(NewUserDb {
             name: user.name,
             email: user.email,
             password: generate_hash(user.password),
         })
         .returning(UserDb::as_returning())
-        .get_result(connection)?;
-
-    Ok(UserDb {
-        id: result.id,
-        name: result.name,
-        email: result.email,
-        password: result.password,
-    })
+        .get_result(connection)
 }
 
 pub fn get_by_id(
     connection: &mut PgConnection,
     user_id: Uuid,
 ) -> Result<UserDb, diesel::result::Error> {
-    let result = users::table
+    users::table
         .select(UserDb::as_select())
         .filter(users::id.eq(user_id))
-        .first::<UserDb>(connection)?;
+        .first(connection)
+}
 
-    Ok(UserDb {
-        id: result.id,
-        name: result.name,
-        email: result.email,
-        password: result.password,
-    })
+pub fn mark_email_verified(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<(), diesel::result::Error> {
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::email_verified_at.eq(diesel::dsl::now),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)?;
+
+    Ok(())
 }
 
+pub fn update_name(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    name: String,
+) -> Result<(), diesel::result::Error> {
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((users::name.eq(name), users::updated_at.eq(diesel::dsl::now)))
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Only called once the link sent to the new email was followed, so it counts
+/// as verified. Fails with a `UniqueViolation` when another account has it.
+pub fn update_email(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    email: String,
+) -> Result<UserDb, diesel::result::Error> {
+    let result = diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::email.eq(email),
+            users::email_verified_at.eq(diesel::dsl::now),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .returning(UserDb::as_returning())
+        .get_result(connection)?;
+
+    Ok(result)
+}
+
+/// Hashes and stores a new password. The password hash doubles as the session
+/// auth hash, so this also logs the user out of every existing session.
+pub fn update_password(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    password: String,
+) -> Result<(), diesel::result::Error> {
+    diesel::update(users::table.filter(users::id.eq(user_id)))
+        .set((
+            users::password.eq(generate_hash(password)),
+            users::updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(connection)?;
+
+    Ok(())
+}
+
+/// Emails are matched case-insensitively, like the unique index on them.
 pub fn get_by_email(
     connection: &mut PgConnection,
     email: String,
 ) -> Result<UserDb, diesel::result::Error> {
-    let result = users::table
+    users::table
         .select(UserDb::as_select())
-        .filter(users::email.eq(email))
-        .first::<UserDb>(connection)?;
-
-    Ok(UserDb {
-        id: result.id,
-        name: result.name,
-        email: result.email,
-        password: result.password,
-    })
+        .filter(lower(users::email).eq(email.trim().to_lowercase()))
+        .first(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use crate::repositories::role_repository;
+
+    fn form(name: &str, email: &str, password: &str) -> RegisterForm {
+        RegisterForm {
+            name: name.to_string(),
+            email: email.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    #[test]
+    fn register_form_trims_valid_input() {
+        let user = form(" Samuel ", " samuel@example.com ", "correct horse")
+            .validate()
+            .unwrap();
+
+        assert_eq!(user.name, "Samuel");
+        assert_eq!(user.email, "samuel@example.com");
+        assert_eq!(user.password, "correct horse");
+    }
+
+    #[test]
+    fn account_forms_check_like_registration() {
+        let name = ProfileForm {
+            name: " Sam ".to_string(),
+        };
+        assert_eq!(name.validate().unwrap(), "Sam");
+        assert!(ProfileForm::default().validate().unwrap_err().get("name").is_some());
+
+        let email = ChangeEmailForm {
+            email: "samuel".to_string(),
+            current_password: "password".to_string(),
+        };
+        assert!(email.validate().unwrap_err().get("email").is_some());
+    }
+
+    #[test]
+    fn debug_output_never_shows_passwords() {
+        let new_user = form("Samuel", "samuel@example.com", "correct horse").validate().unwrap();
+        let credentials = Credentials {
+            email: "samuel@example.com".to_string(),
+            password: "correct horse".to_string(),
+            next: None,
+            ip_address: None,
+            user_agent: None,
+        };
+
+        for debug in [format!("{:?}", new_user), format!("{:?}", credentials)] {
+            assert!(!debug.contains("correct horse"), "{}", debug);
+            assert!(debug.contains("samuel@example.com"));
+        }
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like("50%_off\\"), "50\\%\\_off\\\\");
+        assert_eq!(escape_like("samuel"), "samuel");
+    }
+
+    #[test]
+    fn users_page_by_offset_and_keyset_alike() {
+        let mut connection = test_connection();
+        let tag = Uuid::new_v4().simple().to_string();
+        let users: Vec<UserDb> = ["a", "b", "c"]
+            .iter()
+            .map(|letter| {
+                let user = NewUserDb {
+                    name: format!("{} {}", tag, letter),
+                    email: format!("{}-{}@example.com", letter, tag),
+                    password: "password".to_string(),
+                };
+                create_user(&mut connection, user).unwrap()
+            })
+            .collect();
+        let admin = role_repository::get_by_name(&mut connection, "admin").unwrap();
+        role_repository::assign(&mut connection, users[2].id, admin.id).unwrap();
+
+        let search = UserSearch {
+            q: tag.clone(),
+            sort: UserSort::NameAsc,
+            ..Default::default()
+        };
+        let first = get_users(&mut connection, &search, 2).unwrap();
+        assert_eq!(first.total, 3);
+        assert_eq!(first.pages(), 2);
+        assert_eq!(first.number, Some(1));
+        let ids: Vec<Uuid> = first.items.iter().map(|user| user.id).collect();
+        assert_eq!(ids, [users[0].id, users[1].id]);
+
+        let after = UserSearch {
+            after: first.next_cursor.clone(),
+            ..search.clone()
+        };
+        let second = get_users(&mut connection, &after, 2).unwrap();
+        assert_eq!(second.number, None);
+        assert_eq!(second.next_cursor, None);
+        assert_eq!(second.items[0].id, users[2].id);
+        let offset = UserSearch {
+            page: 2,
+            ..search.clone()
+        };
+        assert_eq!(get_users(&mut connection, &offset, 2).unwrap().items, second.items);
+
+        let admins = UserSearch {
+            role: "admin".to_string(),
+            ..search.clone()
+        };
+        assert_eq!(get_users(&mut connection, &admins, 2).unwrap().total, 1);
+        let tomorrow = UserSearch {
+            created_from: chrono::Utc::now().date_naive().succ_opt(),
+            ..search.clone()
+        };
+        assert_eq!(get_users(&mut connection, &tomorrow, 2).unwrap().total, 0);
+    }
+
+    #[test]
+    fn deleted_users_are_purged_after_the_cutoff() {
+        let mut connection = test_connection();
+        let user = NewUserDb {
+            name: "Samuel".to_string(),
+            email: format!("{}@example.com", Uuid::new_v4()),
+            password: "password".to_string(),
+        };
+        let user = create_user(&mut connection, user).unwrap();
+        let before = chrono::Utc::now().naive_utc() - chrono::Duration::hours(1);
+        let after = chrono::Utc::now().naive_utc() + chrono::Duration::hours(1);
+
+        assert!(!purge(&mut connection, user.id, after).unwrap());
+        mark_deleted(&mut connection, user.id).unwrap();
+        assert!(!get_ids_deleted_before(&mut connection, before, 100).unwrap().contains(&user.id));
+        assert!(!purge(&mut connection, user.id, before).unwrap());
+
+        restore(&mut connection, user.id).unwrap();
+        assert!(!purge(&mut connection, user.id, after).unwrap());
+
+        mark_deleted(&mut connection, user.id).unwrap();
+        assert!(purge(&mut connection, user.id, after).unwrap());
+        assert!(matches!(
+            get_by_id(&mut connection, user.id),
+            Err(diesel::result::Error::NotFound)
+        ));
+    }
+
+    #[test]
+    fn register_form_reports_every_invalid_field() {
+        let errors = form("  ", "samuel", "short").validate().unwrap_err();
+
+        assert!(errors.get("name").is_some());
+        assert!(errors.get("email").is_some());
+        assert!(errors.get("password").is_some());
+    }
 }
@@ -1,7 +1,11 @@
+use std::time::Duration;
 
+use log::{info, warn};
 use tokio::signal;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-async fn shutdown_signal() {
+/// Resolves once SIGINT (Ctrl+C) or SIGTERM is received.
+pub async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -25,4 +29,53 @@ async fn shutdown_signal() {
     }
 
     info!("signal received, starting graceful shutdown");
-}
\ No newline at end of file
+}
+
+/// Coordinates shutting down the server and the background tasks.
+///
+/// The token is cancelled on SIGINT/SIGTERM; the server stops accepting
+/// connections and background tasks stop after their current run, while every
+/// task spawned on the tracker is awaited before the process exits.
+#[derive(Clone)]
+pub struct Shutdown {
+    pub token: CancellationToken,
+    pub tasks: TaskTracker,
+}
+
+impl Shutdown {
+    /// Creates the coordinator and starts listening for shutdown signals.
+    pub fn listen() -> Self {
+        let shutdown = Self {
+            token: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+        };
+
+        let token = shutdown.token.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            token.cancel();
+        });
+
+        shutdown
+    }
+
+    /// Resolves `drain_timeout` after shutdown started, used to stop waiting
+    /// for connections that don't finish in time.
+    pub async fn drain_deadline(&self, drain_timeout: Duration) {
+        self.token.cancelled().await;
+        tokio::time::sleep(drain_timeout).await;
+    }
+
+    /// Waits up to `timeout` for all background tasks to finish.
+    pub async fn wait_for_tasks(&self, timeout: Duration) {
+        self.tasks.close();
+
+        if tokio::time::timeout(timeout, self.tasks.wait()).await.is_err() {
+            warn!(
+                "{} background tasks did not stop within {:?}",
+                self.tasks.len(),
+                timeout
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use log::{error, info};
+use tokio_util::sync::CancellationToken;
+
+use crate::client_info::RequestClient;
+use crate::errors::AppError;
+use crate::models::audit_event::AuditAction;
+use crate::repositories::audit_repository::{self, NewAuditEventDb};
+use crate::repositories::login_throttle_repository;
+use crate::repositories::postgres_store::PostgresStore;
+use crate::repositories::user_repository;
+use crate::shutdown::Shutdown;
+use crate::PgPool;
+
+/// How many deleted users a run looks at per query.
+const BATCH_SIZE: i64 = 100;
+
+/// Starts the purge of deleted accounts past their grace period under a
+/// supervisor, like the session reaper. It also deletes the login throttles
+/// without a failure for `throttle_ttl`, they'd pile up otherwise.
+pub fn spawn(
+    shutdown: &Shutdown,
+    pool: PgPool,
+    store: PostgresStore,
+    period: Duration,
+    grace: chrono::Duration,
+    throttle_ttl: chrono::Duration,
+) {
+    super::supervise(shutdown, "Account purger", move |token| {
+        run(pool.clone(), store.clone(), period, grace, throttle_ttl, token)
+    });
+}
+
+/// Purges accounts every `period` until `token` is cancelled. A failed run is
+/// logged and retried on the next tick.
+async fn run(
+    pool: PgPool,
+    store: PostgresStore,
+    period: Duration,
+    grace: chrono::Duration,
+    throttle_ttl: chrono::Duration,
+    token: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => {
+                info!("Account purger stopped");
+                return;
+            }
+            _ = interval.tick() => {},
+        }
+
+        match purge_deleted(&pool, &store, grace).await {
+            Ok(0) => {}
+            Ok(purged) => info!("Account purger purged {} deleted accounts", purged),
+            Err(err) => error!("Account purger failed: {}", err),
+        }
+
+        match purge_stale_throttles(&pool, throttle_ttl).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("Account purger deleted {} stale login throttles", deleted),
+            Err(err) => error!("Account purger failed to delete login throttles: {}", err),
+        }
+    }
+}
+
+/// Deletes the login throttles whose last failure is older than `ttl`.
+async fn purge_stale_throttles(pool: &PgPool, ttl: chrono::Duration) -> Result<usize, AppError> {
+    let cutoff = chrono::Utc::now().naive_utc() - ttl;
+    let mut connection = pool.get()?;
+
+    let deleted = tokio::task::spawn_blocking(move || {
+        login_throttle_repository::delete_stale(&mut connection, cutoff)
+    })
+    .await??;
+
+    Ok(deleted)
+}
+
+/// Deletes the users deleted more than `grace` ago, their sessions first as
+/// the session table may live outside the cascades. Returns how many.
+pub async fn purge_deleted(
+    pool: &PgPool,
+    store: &PostgresStore,
+    grace: chrono::Duration,
+) -> Result<usize, AppError> {
+    let cutoff = chrono::Utc::now().naive_utc() - grace;
+    let mut purged = 0;
+
+    loop {
+        let mut connection = pool.get()?;
+        let user_ids = tokio::task::spawn_blocking(move || {
+            user_repository::get_ids_deleted_before(&mut connection, cutoff, BATCH_SIZE)
+        })
+        .await??;
+        if user_ids.is_empty() {
+            return Ok(purged);
+        }
+
+        for user_id in user_ids {
+            store.delete_by_user_id(user_id, None).await?;
+
+            let mut connection = pool.get()?;
+            let deleted = tokio::task::spawn_blocking(move || {
+                let deleted = user_repository::purge(&mut connection, user_id, cutoff)?;
+                // Nothing in it points at the user, they are gone for good
+                if deleted {
+                    let event = NewAuditEventDb::new(AuditAction::AccountPurged, &RequestClient::default());
+                    audit_repository::record(&mut connection, event)?;
+                }
+
+                Ok::<_, diesel::result::Error>(deleted)
+            })
+            .await??;
+            if deleted {
+                info!("Purged deleted user {}", user_id);
+                purged += 1;
+            }
+        }
+    }
+}
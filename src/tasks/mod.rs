@@ -0,0 +1,41 @@
+use std::future::Future;
+use std::time::Duration;
+
+use log::error;
+use tokio_util::sync::CancellationToken;
+
+use crate::shutdown::Shutdown;
+
+pub mod account_purger;
+pub mod session_reaper;
+
+/// How long the supervisor waits before restarting a crashed task.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Runs the task `start` makes under a supervisor which restarts it should it
+/// ever panic. The task gets the shutdown token and returns once it is
+/// cancelled, the supervisor stops then too.
+fn supervise<F, Fut>(shutdown: &Shutdown, name: &'static str, start: F)
+where
+    F: Fn(CancellationToken) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let token = shutdown.token.clone();
+
+    shutdown.tasks.spawn(async move {
+        loop {
+            let task = tokio::spawn(start(token.clone()));
+
+            match task.await {
+                Ok(()) => return,
+                Err(err) => {
+                    error!("{} crashed: {}, restarting in {:?}", name, err, RESTART_DELAY);
+                    tokio::select! {
+                        _ = token.cancelled() => return,
+                        _ = tokio::time::sleep(RESTART_DELAY) => {},
+                    }
+                }
+            }
+        }
+    });
+}
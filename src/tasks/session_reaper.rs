@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+use log::{error, info};
+use tokio_util::sync::CancellationToken;
+
+use crate::repositories::postgres_store::PostgresStore;
+use crate::shutdown::Shutdown;
+
+/// Starts the expired session reaper under a supervisor which restarts it
+/// should it ever panic. Both stop once shutdown starts; a run that is already
+/// deleting sessions is allowed to finish first.
+pub fn spawn(shutdown: &Shutdown, store: PostgresStore, period: Duration, batch_size: i64) {
+    super::supervise(shutdown, "Session reaper", move |token| {
+        run(store.clone(), period, batch_size, token)
+    });
+}
+
+/// Deletes expired sessions every `period` until `token` is cancelled. A
+/// failed run is logged and retried on the next tick.
+async fn run(store: PostgresStore, period: Duration, batch_size: i64, token: CancellationToken) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => {
+                info!("Session reaper stopped");
+                return;
+            }
+            _ = interval.tick() => {},
+        }
+
+        match store.delete_expired_in_batches(batch_size).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("Session reaper deleted {} expired sessions", deleted),
+            Err(err) => error!("Session reaper failed: {}", err),
+        }
+    }
+}
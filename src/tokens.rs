@@ -0,0 +1,36 @@
+use blake2::{Blake2s256, Digest};
+use rand_core::{OsRng, RngCore};
+
+/// A random, URL safe token for links sent by email, such as password resets.
+pub fn generate() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    to_hex(&bytes)
+}
+
+/// What gets stored instead of the token. Tokens are random enough that a
+/// fast hash is fine, and a leaked table doesn't hand out working links.
+pub fn hash(token: &str) -> String {
+    to_hex(&Blake2s256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_unique_and_hashed_to_hex() {
+        let token = generate();
+
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate());
+        assert_eq!(hash(&token), hash(&token));
+        assert_ne!(hash(&token), token);
+        assert!(hash(&token).chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
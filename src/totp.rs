@@ -0,0 +1,198 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand_core::{OsRng, RngCore};
+use sha1::Sha1;
+use uuid::Uuid;
+
+/// RFC 6238 with the parameters every authenticator app supports: SHA-1,
+/// 30 second steps and 6 digits.
+const SECRET_LENGTH: usize = 20;
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes of the step before and after are accepted too, for clock drift.
+const SKEW_STEPS: i64 = 1;
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+
+    secret
+}
+
+/// The secret as authenticator apps want it typed in.
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+pub fn step_at(unix_secs: i64) -> i64 {
+    unix_secs.div_euclid(STEP_SECS)
+}
+
+pub fn code_at_step(secret: &[u8], step: i64) -> String {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+
+    format!("{:0width$}", value % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// The step `code` belongs to if it is valid around `unix_secs`. Steps up to
+/// `last_step` were used already and are refused, so codes can't be replayed.
+pub fn verify(secret: &[u8], code: &str, unix_secs: i64, last_step: Option<i64>) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = step_at(unix_secs);
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|step| last_step.is_none_or(|last_step| *step > last_step))
+        .find(|step| constant_time_eq(code_at_step(secret, *step).as_bytes(), code.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The `otpauth://` URI authenticator apps scan to add the account.
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+        secret = encode_secret(secret),
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// `data` as a QR code, an `<svg>` element to inline in a page.
+pub fn qr_code_svg(data: &str) -> String {
+    // Provisioning URIs are a few hundred bytes, far below the QR code limit
+    let code = QrCode::new(data.as_bytes()).expect("provisioning URIs fit in a QR code");
+    let image = code
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .quiet_zone(true)
+        .build();
+
+    // Drop the XML declaration, it has no place inside HTML
+    match image.find("<svg") {
+        Some(start) => image[start..].to_string(),
+        None => image,
+    }
+}
+
+/// Encrypts TOTP secrets at rest. Each secret is bound to its user, so a
+/// ciphertext copied to another row doesn't decrypt.
+#[derive(Clone)]
+pub struct SecretCipher(XChaCha20Poly1305);
+
+impl std::fmt::Debug for SecretCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretCipher(..)")
+    }
+}
+
+impl SecretCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self(XChaCha20Poly1305::new(key.into()))
+    }
+
+    /// The random nonce followed by the ciphertext.
+    pub fn encrypt(&self, user_id: Uuid, secret: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: secret,
+            aad: user_id.as_bytes(),
+        };
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, payload)
+            .expect("encryption only fails for oversized messages");
+
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    /// `None` when the data was encrypted with another key or for another
+    /// user, or was tampered with.
+    pub fn decrypt(&self, user_id: Uuid, sealed: &[u8]) -> Option<Vec<u8>> {
+        let nonce_length = XNonce::default().len();
+        if sealed.len() < nonce_length {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(nonce_length);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: user_id.as_bytes(),
+        };
+
+        self.0.decrypt(XNonce::from_slice(nonce), payload).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 test vectors of RFC 6238, appendix B, cut to 6 digits.
+    #[test]
+    fn codes_match_the_rfc_test_vectors() {
+        let secret = b"12345678901234567890";
+
+        for (unix_secs, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(code_at_step(secret, step_at(unix_secs)), code);
+        }
+    }
+
+    #[test]
+    fn verify_allows_drift_but_no_replays() {
+        let secret = b"12345678901234567890";
+        let now = 1_700_000_000;
+        let step = step_at(now);
+        let previous = code_at_step(secret, step - 1);
+
+        assert_eq!(verify(secret, &previous, now, None), Some(step - 1));
+        assert_eq!(verify(secret, &previous, now, Some(step - 1)), None);
+        assert_eq!(verify(secret, &code_at_step(secret, step - 2), now, None), None);
+        assert_eq!(verify(secret, "12345", now, None), None);
+        assert_eq!(verify(secret, "abcdef", now, None), None);
+    }
+
+    #[test]
+    fn secrets_only_decrypt_for_their_user_and_key() {
+        let cipher = SecretCipher::new(&[7; 32]);
+        let user_id = Uuid::new_v4();
+        let sealed = cipher.encrypt(user_id, b"secret");
+
+        assert_eq!(cipher.decrypt(user_id, &sealed).as_deref(), Some(&b"secret"[..]));
+        assert_eq!(cipher.decrypt(Uuid::new_v4(), &sealed), None);
+        assert_eq!(SecretCipher::new(&[8; 32]).decrypt(user_id, &sealed), None);
+        assert_eq!(cipher.decrypt(user_id, &sealed[..4]), None);
+    }
+}
@@ -0,0 +1,97 @@
+use serde::Deserialize;
+
+/// Per field error messages for a submitted form, kept in the order they were
+/// found so templates can show the first problem with each field.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidationErrors(Vec<(&'static str, String)>);
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.push((field, message.into()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The first message recorded for `field`, if any.
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(name, _)| *name == field)
+            .map(|(_, message)| message.as_str())
+    }
+
+    /// `Ok(value)` when nothing was recorded, the errors otherwise.
+    pub fn into_result<T>(self, value: T) -> Result<T, Self> {
+        match self.is_empty() {
+            true => Ok(value),
+            false => Err(self),
+        }
+    }
+}
+
+/// A deliberately loose check: one `@` with something on both sides, a dot in
+/// the domain and no whitespace. Whether the address exists is only known once
+/// we send mail to it.
+pub fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+
+    !local.is_empty()
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+        && domain
+            .split_once('.')
+            .map(|(name, tld)| !name.is_empty() && !tld.is_empty() && !domain.ends_with('.'))
+            .unwrap_or_default()
+}
+
+/// For optional query string and form fields: forms submit empty inputs as
+/// empty strings rather than leaving them out.
+pub fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.trim().is_empty() => {
+            value.trim().parse().map(Some).map_err(serde::de::Error::custom)
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn email_format() {
+        assert!(is_valid_email("samuel@example.com"));
+        assert!(is_valid_email("s.h+todo@mail.example.co"));
+        assert!(!is_valid_email(""));
+        assert!(!is_valid_email("samuel"));
+        assert!(!is_valid_email("@example.com"));
+        assert!(!is_valid_email("samuel@"));
+        assert!(!is_valid_email("samuel@localhost"));
+        assert!(!is_valid_email("samuel@example."));
+        assert!(!is_valid_email("sam uel@example.com"));
+        assert!(!is_valid_email("samuel@ex@ample.com"));
+    }
+
+    #[test]
+    fn errors_keep_the_first_message_per_field() {
+        let mut errors = ValidationErrors::default();
+        assert_eq!(errors.clone().into_result(1), Ok(1));
+
+        errors.add("email", "first");
+        errors.add("email", "second");
+
+        assert_eq!(errors.get("email"), Some("first"));
+        assert_eq!(errors.get("name"), None);
+        assert!(errors.into_result(1).is_err());
+    }
+}